@@ -24,7 +24,7 @@ fn main() {
     }
 
     let request = client1.update_request();
-    // serialize for some kind of transport, lets try json
+    // serialize as a transport would, lets try json
     let request = serde_json::to_string(&request).unwrap();
 
     // #####################
@@ -55,7 +55,7 @@ fn main() {
     }
 
     let request2 = client1.update_request();
-    // serialize for some kind of transport, lets try json
+    // serialize as a transport would, lets try json
     let request2 = serde_json::to_string(&request2).unwrap();
 
     // #####################
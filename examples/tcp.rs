@@ -0,0 +1,40 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use diff::Diff;
+use diffsync::{
+    client::Client,
+    tcp::{serve, ClientDriver, SharedClient, SharedServer},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpListener, sync::Mutex};
+
+#[derive(Deserialize, Serialize, Diff, Debug, Clone, Hash, PartialEq, Default)]
+#[diff(attr(#[derive(Serialize, Deserialize)]))]
+pub struct Data {
+    pub data: BTreeMap<u32, String>,
+}
+
+#[tokio::main]
+async fn main() {
+    let server: SharedServer<Data, u32> = Default::default();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    println!("Serving on {addr}");
+    tokio::spawn(serve(listener, server.clone()));
+
+    let client: SharedClient<Data, u32> = Arc::new(Mutex::new(Client::with_id(1337)));
+    ClientDriver::default()
+        .with_poll_interval(Duration::from_millis(100))
+        .spawn(addr, client.clone());
+
+    for i in 0..10 {
+        server
+            .write()
+            .await
+            .state
+            .data
+            .insert(i, format!("String {i}"));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        println!("Client has {} values", client.lock().await.state.data.len());
+    }
+}
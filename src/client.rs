@@ -1,81 +1,495 @@
-use twox_hash::XxHash64;
+use std::{
+    fs,
+    io::{self, Read, Write},
+    marker::PhantomData,
+    path::Path,
+};
 
-use crate::customhash::CustomHash;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    codec::{self, ContentType},
+    conflict::now_millis,
+    customhash::XxHash64,
+    merkle::{self, MerkleMap, ReconcileRequest, ReconcileResponse},
+    stats::ApplyFailures,
+    trace::{self, trace_event, TracedId},
+};
 
 use super::*;
 
+/// Computes the diff that undoes a diff, before it is applied to the state, like
+/// [`crate::structs::InvertDiff::invert`]
+pub type UndoFn<STATE> = fn(&STATE, &<STATE as Diff>::Repr) -> <STATE as Diff>::Repr;
+
+/// A client of a [`crate::server::Server`], which has to use the same [`HashAlgorithm`]
 #[cfg_attr(feature = "bevy_support", derive(bevy::prelude::Resource))]
-pub struct Client<STATE: Default, ID> {
+pub struct Client<STATE: Default + Diff, ID, H = XxHash64> {
     id: ID,
     pub state: STATE,
+    // The last state confirmed by the server, only kept by clients that push their changes
+    baseline: Option<Baseline<STATE>>,
+    // Hash of the last state confirmed by the server, local changes are detected against it
+    baseline_hash: StateHash,
+    failures: ApplyFailures,
+    undo: Option<UndoFn<STATE>>,
+    // Set when an update fails, until the next one succeeds
+    needs_resync: bool,
+    // Set when the server didn't know the base of a push, until the next update succeeds
+    attach_base: bool,
+    /// Trace id of the last update request, for the span applying its answer
+    #[cfg(feature = "tracing")]
+    trace_id: std::sync::atomic::AtomicU64,
+    hasher: PhantomData<fn() -> H>,
+}
+
+/// The last state confirmed by the server, and how to copy states, which a client that pushes
+/// does whenever the server confirms its changes
+struct Baseline<STATE> {
+    state: STATE,
+    copy: fn(&STATE) -> STATE,
+}
+
+impl<STATE: Clone> Baseline<STATE> {
+    fn new(state: STATE) -> Self {
+        Self {
+            state,
+            copy: STATE::clone,
+        }
+    }
 }
 
-impl<STATE: Hash + Diff + Default, ID: Clone> Client<STATE, ID> {
+impl<STATE: Hash + Diff + Default, ID: Clone + TracedId, H: HashAlgorithm> Client<STATE, ID, H> {
     /// Create a new client with the given id, the ID is used to differentiate on the server side
     pub fn with_id(id: ID) -> Self {
+        let state = STATE::default();
         Self {
             id,
-            state: Default::default(),
+            baseline_hash: H::hash_of(&state),
+            state,
+            baseline: None,
+            failures: Default::default(),
+            undo: None,
+            needs_resync: false,
+            attach_base: false,
+            #[cfg(feature = "tracing")]
+            trace_id: Default::default(),
+            hasher: PhantomData,
         }
     }
+    /// Keep a copy of the last state confirmed by the server, so that local changes can be
+    /// pushed with [`Client::push_request`]. Clients that only follow the server don't need it,
+    /// and save keeping the state twice.
+    ///
+    /// The copy also makes applying updates atomic without [`Client::with_undo`], see
+    /// [`Client::apply_update`]
+    pub fn with_push(mut self) -> Self
+    where
+        STATE: Clone,
+    {
+        self.baseline = Some(Baseline::new(self.state.clone()));
+        self.baseline_hash = self.calculate_hash();
+        self
+    }
+
+    /// Roll back diffs that don't give the expected state with the undo computed by `undo`, so
+    /// that a failed update leaves the state as it was. For maps diffed with a [`SimpleDiff`]
+    /// that is `|state, diff| diff.invert(state)`
+    pub fn with_undo(mut self, undo: UndoFn<STATE>) -> Self {
+        self.undo = Some(undo);
+        self
+    }
+
     pub fn id(&self) -> ID {
         self.id.clone()
     }
 
-    fn calculate_hash(&self) -> u64 {
-        let mut h = CustomHash::new();
-        self.state.hash(&mut h);
-        h.finish()
+    fn calculate_hash(&self) -> StateHash {
+        H::hash_of(&self.state)
     }
 
     pub fn update_request(&self) -> ClientUpdateRequest<ID> {
+        let trace_id = trace::new_trace_id();
+        #[cfg(feature = "tracing")]
+        let _span = {
+            self.trace_id.store(
+                trace_id.unwrap_or_default(),
+                std::sync::atomic::Ordering::Relaxed,
+            );
+            tracing::debug_span!("update_request", client = ?self.id, trace_id).entered()
+        };
+        let current_hash = self.calculate_hash();
+        trace_event!(tracing::Level::TRACE, hash = %trace::Hex(current_hash));
         ClientUpdateRequest {
             id: self.id.clone(),
-            current_hash: self.calculate_hash(),
+            current_hash,
+            hash_kind: H::KIND,
+            trace_id,
+            force_complete: self.needs_resync,
+        }
+    }
+
+    /// Whether the next update request asks for a complete update, because applying an update
+    /// failed since the last one that succeeded
+    pub fn needs_resync(&self) -> bool {
+        self.needs_resync
+    }
+
+    /// Ask for a complete update with the next update request, discarding any local changes
+    pub fn request_resync(&mut self) {
+        self.needs_resync = true;
+    }
+
+    /// Acknowledge the current state to the server, should be sent after an update has been
+    /// applied successfully. Sending it is optional, the next update request carries the same
+    /// information, but without it the server has to keep an extra baseline for the client
+    pub fn acknowledgement(&self) -> ClientAck<ID> {
+        ClientAck {
+            id: self.id.clone(),
+            hash: self.calculate_hash(),
+        }
+    }
+
+    /// Whether the state has been modified since it was last updated by the server
+    pub fn has_local_changes(&self) -> bool {
+        self.calculate_hash() != self.baseline_hash
+    }
+
+    /// Changes made to the state since it was last updated by the server, to be pushed to the
+    /// server instead of an update request. Returns `None` if there are no local changes, or if
+    /// the client was not created [`Client::with_push`].
+    ///
+    /// Local changes are discarded by a complete update, so a client with local changes should
+    /// push them rather than request an update
+    pub fn push_request(&self) -> Option<ClientPush<ID, STATE::Repr>> {
+        let baseline = self.baseline.as_ref()?;
+        self.has_local_changes().then(|| ClientPush {
+            id: self.id.clone(),
+            base_hash: self.baseline_hash,
+            diff: baseline.state.diff(&self.state),
+            base: self
+                .attach_base
+                .then(|| STATE::identity().diff(&baseline.state)),
+            timestamp: now_millis(),
+            trace_id: trace::new_trace_id(),
+        })
+    }
+
+    /// Act on a push the server rejected. If the server didn't know the base of the push, the
+    /// next push attaches it, so that the server can still merge the local changes. A push that
+    /// can't be applied at all is given up: the local changes are discarded, and the next update
+    /// request asks for a complete update
+    pub fn push_rejected(&mut self, error: &PushError) {
+        match error {
+            PushError::UnknownBase { .. } => self.attach_base = true,
+            PushError::InvalidPush { .. } => {
+                if let Some(baseline) = &self.baseline {
+                    self.state = (baseline.copy)(&baseline.state);
+                }
+                self.needs_resync = true;
+            }
+        }
+    }
+
+    /// Start reconciling the map selected from the state with the server, instead of getting a
+    /// complete update. See [`crate::merkle`]
+    pub fn reconcile_request<M: MerkleMap>(
+        &self,
+        map: impl Fn(&STATE) -> &M,
+    ) -> ReconcileRequest<M::Key> {
+        merkle::start::<H, M>(map(&self.state))
+    }
+
+    /// Apply the answer to a reconciliation request, returning the next request to send, or
+    /// `None` once the map matches the server. The state is then taken as the one last sent by
+    /// the server, so any local changes are lost
+    pub fn apply_reconcile<M: MerkleMap>(
+        &mut self,
+        map: impl Fn(&mut STATE) -> &mut M,
+        response: ReconcileResponse<M::Key, M::Value>,
+    ) -> Option<ReconcileRequest<M::Key>>
+    where
+        STATE: Clone,
+    {
+        let next = merkle::apply::<H, M>(map(&mut self.state), response);
+        if next.is_none() {
+            if let Some(baseline) = &mut self.baseline {
+                baseline.state = self.state.clone();
+            }
+            self.baseline_hash = self.calculate_hash();
+            self.needs_resync = false;
         }
+        next
+    }
+
+    /// How often applying an update has failed, by error
+    pub fn apply_failures(&self) -> ApplyFailures {
+        self.failures
     }
 
+    /// Apply an update from the server. On any error the client asks for a complete update
+    /// next, see [`Client::needs_resync`], except when the server hashes with another algorithm,
+    /// which no complete update fixes.
+    ///
+    /// A complete update is built on the side, and only kept if it has the hash the server
+    /// expects. A diff is applied to the state in place, and rolled back if the result doesn't
+    /// have the expected hash by clients created [`Client::with_undo`] or [`Client::with_push`].
+    /// Other clients keep no copy to roll back to, their state is only repaired by the complete
+    /// update
     pub fn apply_update(
         &mut self,
         client_update: ClientUpdate<STATE::Repr>,
     ) -> Result<(), UpdateError> {
-        let currenthash = self.calculate_hash();
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "apply_update",
+            client = ?self.id,
+            trace_id = self.trace_id.load(std::sync::atomic::Ordering::Relaxed),
+            kind = trace::update_kind(&client_update),
+            newhash = %trace::Hex(client_update.newhash()),
+        )
+        .entered();
+        let result = self.try_apply_update(client_update);
+        if let Err(error) = &result {
+            self.failures.record(error);
+        }
+        match result {
+            Err(UpdateError::HashKindMismatch { .. }) => {}
+            Err(_) => self.needs_resync = true,
+            Ok(()) => {
+                self.needs_resync = false;
+                self.attach_base = false;
+            }
+        }
+        result
+    }
+
+    fn try_apply_update(
+        &mut self,
+        client_update: ClientUpdate<STATE::Repr>,
+    ) -> Result<(), UpdateError> {
+        if client_update.hash_kind() != H::KIND {
+            trace_event!(
+                tracing::Level::ERROR,
+                client = ?H::KIND,
+                server = ?client_update.hash_kind(),
+                "client and server hash with different algorithms"
+            );
+            return Err(UpdateError::HashKindMismatch {
+                client: H::KIND,
+                server: client_update.hash_kind(),
+            });
+        }
         match client_update {
             ClientUpdate::Complete {
                 complete_diff,
                 newhash,
+                ..
             } => {
-                self.state = STATE::identity();
-                let before_apply = self.calculate_hash();
-                log::info!("before apply: {before_apply:X}");
-                self.state.apply(&complete_diff);
-                let myhash = self.calculate_hash();
-                log::info!("calucalted hash: {myhash:X}");
-                log::info!("expected hash: {newhash:X}");
-                // state
-                if newhash == self.calculate_hash() {
-                    return Ok(());
-                } else {
-                    return Err(UpdateError::HashResultDiff);
+                // nothing of the current state is reused, so build the new one on the side
+                let mut next = STATE::identity();
+                next.apply(&complete_diff);
+                check_hash(H::hash_of(&next), newhash)?;
+                if let Some(baseline) = &mut self.baseline {
+                    baseline.state = (baseline.copy)(&next);
                 }
+                self.state = next;
+                self.baseline_hash = newhash;
+                Ok(())
             }
             ClientUpdate::Diff {
                 diff,
                 newhash,
                 oldhash,
+                ..
             } => {
-                if currenthash == oldhash {
-                    self.state.apply(&diff);
-
-                    if newhash == self.calculate_hash() {
-                        return Ok(());
+                let current = self.calculate_hash();
+                if current != oldhash {
+                    trace_event!(
+                        tracing::Level::INFO,
+                        oldhash = %trace::Hex(oldhash),
+                        "update is for a state the client is no longer in"
+                    );
+                    return Err(UpdateError::InvalidUpdateStartState {
+                        expected: oldhash,
+                        actual: current,
+                    });
+                }
+                let local_changes = oldhash != self.baseline_hash;
+                self.apply_diff(&diff, newhash, local_changes)?;
+                if let Some(baseline) = &mut self.baseline {
+                    if local_changes {
+                        // the update also confirms the local changes the server merged
+                        baseline.state = (baseline.copy)(&self.state);
                     } else {
-                        return Err(UpdateError::HashResultDiff);
+                        baseline.state.apply(&diff);
                     }
-                } else {
-                    return Err(UpdateError::InvalidUpdateStartState);
                 }
+                self.baseline_hash = newhash;
+                Ok(())
+            }
+        }
+    }
+
+    /// Apply a diff to the state, which is rolled back if it doesn't end up with `newhash`,
+    /// unless the client has neither an undo nor a baseline to roll back with
+    fn apply_diff(
+        &mut self,
+        diff: &STATE::Repr,
+        newhash: StateHash,
+        local_changes: bool,
+    ) -> Result<(), UpdateError> {
+        match (self.undo, &self.baseline) {
+            (Some(undo), _) => {
+                let undo = undo(&self.state, diff);
+                self.state.apply(diff);
+                let result = check_hash(self.calculate_hash(), newhash);
+                if result.is_err() {
+                    self.state.apply(&undo);
+                }
+                result
+            }
+            (None, Some(_)) if !local_changes => {
+                // the baseline doubles as the copy to roll back to
+                self.state.apply(diff);
+                let result = check_hash(self.calculate_hash(), newhash);
+                if let (Err(_), Some(baseline)) = (&result, &self.baseline) {
+                    self.state = (baseline.copy)(&baseline.state);
+                }
+                result
             }
+            (None, Some(baseline)) => {
+                // the local changes are only in the state, so apply the diff to a copy of it
+                let mut next = (baseline.copy)(&self.state);
+                next.apply(diff);
+                check_hash(H::hash_of(&next), newhash)?;
+                self.state = next;
+                Ok(())
+            }
+            (None, None) => {
+                // nothing to roll back with, the complete update asked for next repairs the state
+                self.state.apply(diff);
+                check_hash(self.calculate_hash(), newhash)
+            }
+        }
+    }
+}
+
+impl<STATE, ID, H> Client<STATE, ID, H>
+where
+    STATE: Hash + Clone + Diff + Default + Serialize + DeserializeOwned,
+    ID: Clone + TracedId + Serialize + DeserializeOwned,
+    H: HashAlgorithm,
+{
+    /// Write the id, the state and the last state confirmed by the server, so that a restored
+    /// client gets a diff from a server that still remembers it. Clients that don't push only
+    /// write the hash of the confirmed state. The content type is written
+    /// first, so it doesn't have to be known when restoring
+    pub fn save_to<W: Write>(&self, writer: W, content_type: ContentType) -> io::Result<()> {
+        let state_hash = self.calculate_hash();
+        let saved = SavedClient {
+            id: &self.id,
+            hash_kind: H::KIND,
+            state: &self.state,
+            state_hash,
+            push: self.baseline.is_some(),
+            baseline: self
+                .baseline
+                .as_ref()
+                .map(|baseline| &baseline.state)
+                .filter(|_| state_hash != self.baseline_hash),
+            baseline_hash: self.baseline_hash,
+        };
+        codec::write_tagged(writer, content_type, &saved)
+    }
+
+    /// Restore a client written by [`Client::save_to`]. Fails if it was saved with another hash
+    /// algorithm, or if the state or the confirmed state no longer have the saved hashes
+    pub fn restore_from<R: Read>(reader: R) -> io::Result<Self> {
+        let saved: SavedClient<ID, STATE> = codec::read_tagged(reader)?;
+        if saved.hash_kind != H::KIND {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "client was saved hashing with {:?}, not {:?}",
+                    saved.hash_kind,
+                    H::KIND
+                ),
+            ));
+        }
+        let mismatch = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "saved client state doesn't match its hash",
+            )
+        };
+        if H::hash_of(&saved.state) != saved.state_hash {
+            return Err(mismatch());
+        }
+        let baseline = match (saved.push, saved.baseline) {
+            (false, _) => None,
+            (true, Some(baseline)) => Some(baseline),
+            (true, None) => Some(saved.state.clone()),
+        };
+        let baseline_hash = match &baseline {
+            Some(baseline) => H::hash_of(baseline),
+            None => saved.baseline_hash,
+        };
+        if baseline_hash != saved.baseline_hash {
+            return Err(mismatch());
         }
+        Ok(Self {
+            id: saved.id,
+            state: saved.state,
+            baseline: baseline.map(Baseline::new),
+            baseline_hash: saved.baseline_hash,
+            failures: Default::default(),
+            undo: None,
+            needs_resync: false,
+            attach_base: false,
+            #[cfg(feature = "tracing")]
+            trace_id: Default::default(),
+            hasher: PhantomData,
+        })
+    }
+
+    /// Save to a file, replacing it atomically, see [`Client::save_to`]
+    pub fn save(&self, path: impl AsRef<Path>, content_type: ContentType) -> io::Result<()> {
+        codec::replace_file(path.as_ref(), |file| self.save_to(file, content_type))
+    }
+
+    /// Restore a client saved with [`Client::save`]
+    pub fn restore(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::restore_from(io::BufReader::new(fs::File::open(path)?))
+    }
+}
+
+/// What [`Client::save_to`] writes, borrowed when saving and owned when restoring. The
+/// confirmed state is only saved by clients that push, when it differs from the state
+#[derive(Serialize, Deserialize)]
+struct SavedClient<ID, STATE> {
+    id: ID,
+    hash_kind: HashKind,
+    state: STATE,
+    state_hash: StateHash,
+    push: bool,
+    baseline: Option<STATE>,
+    baseline_hash: StateHash,
+}
+
+fn check_hash(myhash: StateHash, newhash: StateHash) -> Result<(), UpdateError> {
+    if myhash != newhash {
+        log::debug!("Applied update gives hash {myhash:X}, expected {newhash:X}");
+        trace_event!(
+            tracing::Level::WARN,
+            calculated = %trace::Hex(myhash),
+            expected = %trace::Hex(newhash),
+            "state hash differs from the server after applying the update"
+        );
+        return Err(UpdateError::HashResultDiff {
+            expected: newhash,
+            actual: myhash,
+        });
     }
+    Ok(())
 }
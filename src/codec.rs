@@ -0,0 +1,467 @@
+//! Serialization of the protocol messages, with one feature gated [`Codec`] per format, and a
+//! framing helper that tags every frame with its [`ContentType`] so that a server can answer
+//! clients in whatever format they use.
+//!
+//! A frame is a big endian `u32` length, followed by that many bytes: the content type tag, the
+//! [`Compression`] flag and the encoded message, compressed if the flag says so.
+
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use serde::de::DeserializeOwned;
+
+use crate::compression::{Compression, CompressionConfig};
+
+use super::*;
+
+/// Largest frame accepted by [`decode_frame`], anything bigger is treated as corrupt
+pub const MAX_FRAME_LEN: usize = 256 * 1024 * 1024;
+
+/// Bytes preceding the payload in a frame, the length, the content type tag and the
+/// compression flag
+pub const FRAME_HEADER_LEN: usize = 6;
+
+/// A serialization format for the protocol messages
+pub trait Codec {
+    const CONTENT_TYPE: ContentType;
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// The formats there are codecs for, the discriminant is the tag used in frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum ContentType {
+    Json = 1,
+    Bincode = 2,
+    Postcard = 3,
+    MessagePack = 4,
+    Cbor = 5,
+}
+
+impl ContentType {
+    pub fn tag(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_tag(tag: u8) -> Result<Self, CodecError> {
+        Ok(match tag {
+            1 => Self::Json,
+            2 => Self::Bincode,
+            3 => Self::Postcard,
+            4 => Self::MessagePack,
+            5 => Self::Cbor,
+            tag => return Err(CodecError::UnknownContentType(tag)),
+        })
+    }
+
+    /// The media type, for transports like HTTP that have a header for it
+    pub fn mime(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Bincode => "application/x-bincode",
+            Self::Postcard => "application/x-postcard",
+            Self::MessagePack => "application/msgpack",
+            Self::Cbor => "application/cbor",
+        }
+    }
+
+    /// Encode with the codec for this content type, fails if its feature is not enabled
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            #[cfg(feature = "json")]
+            Self::Json => Json::encode(value),
+            #[cfg(feature = "bincode")]
+            Self::Bincode => Bincode::encode(value),
+            #[cfg(feature = "postcard")]
+            Self::Postcard => Postcard::encode(value),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack::encode(value),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => Cbor::encode(value),
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = value;
+                Err(CodecError::Unsupported(self))
+            }
+        }
+    }
+
+    /// Decode with the codec for this content type, fails if its feature is not enabled
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, CodecError> {
+        match self {
+            #[cfg(feature = "json")]
+            Self::Json => Json::decode(bytes),
+            #[cfg(feature = "bincode")]
+            Self::Bincode => Bincode::decode(bytes),
+            #[cfg(feature = "postcard")]
+            Self::Postcard => Postcard::decode(bytes),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack::decode(bytes),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => Cbor::decode(bytes),
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = bytes;
+                Err(CodecError::Unsupported(self))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    /// The codec for the content type is not enabled
+    Unsupported(ContentType),
+    UnknownContentType(u8),
+    /// The feature for the compression algorithm is not enabled
+    UnsupportedCompression(Compression),
+    UnknownCompression(u8),
+    FrameTooLarge(usize),
+    /// The message would decompress to more than the configured maximum
+    DecompressedTooLarge(usize),
+    Encode(Box<dyn std::error::Error + Send + Sync>),
+    Decode(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsupported(content_type) => {
+                write!(f, "codec for {content_type:?} is not enabled")
+            }
+            Self::UnknownContentType(tag) => write!(f, "unknown content type tag {tag}"),
+            Self::UnsupportedCompression(compression) => {
+                write!(f, "compression {compression:?} is not enabled")
+            }
+            Self::UnknownCompression(tag) => write!(f, "unknown compression flag {tag}"),
+            Self::FrameTooLarge(len) => write!(f, "frame of {len} bytes is too large"),
+            Self::DecompressedTooLarge(max) => {
+                write!(f, "message decompresses to more than {max} bytes")
+            }
+            Self::Encode(e) => write!(f, "failed to encode: {e}"),
+            Self::Decode(e) => write!(f, "failed to decode: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<CodecError> for std::io::Error {
+    fn from(e: CodecError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Encode a message into an uncompressed frame
+pub fn encode_frame<T: Serialize + ?Sized>(
+    content_type: ContentType,
+    value: &T,
+) -> Result<Vec<u8>, CodecError> {
+    encode_frame_with(content_type, &CompressionConfig::default(), value)
+}
+
+/// Encode a message into a frame, compressed if the configuration says so
+pub fn encode_frame_with<T: Serialize + ?Sized>(
+    content_type: ContentType,
+    compression: &CompressionConfig,
+    value: &T,
+) -> Result<Vec<u8>, CodecError> {
+    let (algorithm, payload) = compression.compress(content_type.encode(value)?)?;
+    let len = payload.len() + FRAME_HEADER_LEN - 4;
+    if len > MAX_FRAME_LEN {
+        return Err(CodecError::FrameTooLarge(len));
+    }
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(len as u32).to_be_bytes());
+    frame.push(content_type.tag());
+    frame.push(algorithm.tag());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Length of the frame at the start of `buf`, header included, or `None` if not even the
+/// length has arrived yet
+pub fn frame_len(buf: &[u8]) -> Result<Option<usize>, CodecError> {
+    let Some(len) = buf.get(..4) else {
+        return Ok(None);
+    };
+    let len = u32::from_be_bytes(len.try_into().expect("slice of four")) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(CodecError::FrameTooLarge(len));
+    }
+    Ok(Some(len + 4))
+}
+
+/// Decode the frame at the start of `buf`, returning the message with its content type and the
+/// number of bytes consumed, or `None` if the frame is not complete yet
+pub fn decode_frame<T: DeserializeOwned>(
+    buf: &[u8],
+) -> Result<Option<(ContentType, T, usize)>, CodecError> {
+    decode_frame_with(buf, &CompressionConfig::default())
+}
+
+/// Like [`decode_frame`], with the limits of the configuration applied to compressed frames
+pub fn decode_frame_with<T: DeserializeOwned>(
+    buf: &[u8],
+    compression: &CompressionConfig,
+) -> Result<Option<(ContentType, T, usize)>, CodecError> {
+    let Some(len) = frame_len(buf)? else {
+        return Ok(None);
+    };
+    let Some(body) = buf.get(4..len) else {
+        return Ok(None);
+    };
+    let (content_type, value) = decode_frame_body(body, compression)?;
+    Ok(Some((content_type, value, len)))
+}
+
+/// Decode what follows the length of a frame, for transports that read the length themselves
+pub fn decode_frame_body<T: DeserializeOwned>(
+    body: &[u8],
+    compression: &CompressionConfig,
+) -> Result<(ContentType, T), CodecError> {
+    let [tag, flag, payload @ ..] = body else {
+        return Err(CodecError::Decode("frame without header".into()));
+    };
+    let content_type = ContentType::from_tag(*tag)?;
+    let payload = compression.decompress(Compression::from_tag(*flag)?, payload)?;
+    Ok((content_type, content_type.decode(&payload)?))
+}
+
+/// Write a value preceded by the tag of its content type, so that it can be read back without
+/// knowing the content type, for saving state
+pub(crate) fn write_tagged<W: Write, T: Serialize + ?Sized>(
+    mut writer: W,
+    content_type: ContentType,
+    value: &T,
+) -> io::Result<()> {
+    let bytes = content_type.encode(value)?;
+    writer.write_all(&[content_type.tag()])?;
+    writer.write_all(&bytes)?;
+    writer.flush()
+}
+
+/// Read a value written by [`write_tagged`]
+pub(crate) fn read_tagged<R: Read, T: DeserializeOwned>(mut reader: R) -> io::Result<T> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let Some((&tag, payload)) = bytes.split_first() else {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    };
+    Ok(ContentType::from_tag(tag)?.decode(payload)?)
+}
+
+/// Write a file through a temporary one that is renamed over it, so that a crash while writing
+/// leaves the previous file intact. The temporary file is named uniquely, so that concurrent
+/// saves to the same path don't write into each other's
+pub(crate) fn replace_file(
+    path: &Path,
+    write: impl FnOnce(&mut io::BufWriter<&mut fs::File>) -> io::Result<()>,
+) -> io::Result<()> {
+    static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = fs::File::create(&tmp).and_then(|mut file| {
+        write(&mut io::BufWriter::new(&mut file))?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    });
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written?;
+    sync_parent(path)
+}
+
+/// Make a rename in the directory of `path` durable
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::File::open(parent)?.sync_all()
+}
+
+/// Directories can't be opened to be synced on other platforms, where renames are made durable
+/// by the file system
+#[cfg(not(unix))]
+fn sync_parent(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    const CONTENT_TYPE: ContentType = ContentType::Json;
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(|e| CodecError::Encode(e.into()))
+    }
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(|e| CodecError::Decode(e.into()))
+    }
+}
+
+#[cfg(feature = "bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    const CONTENT_TYPE: ContentType = ContentType::Bincode;
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(value).map_err(|e| CodecError::Encode(e.into()))
+    }
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        bincode::deserialize(bytes).map_err(|e| CodecError::Decode(e.into()))
+    }
+}
+
+#[cfg(feature = "postcard")]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    const CONTENT_TYPE: ContentType = ContentType::Postcard;
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, CodecError> {
+        postcard::to_allocvec(value).map_err(|e| CodecError::Encode(e.into()))
+    }
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        postcard::from_bytes(bytes).map_err(|e| CodecError::Decode(e.into()))
+    }
+}
+
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    const CONTENT_TYPE: ContentType = ContentType::MessagePack;
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec_named(value).map_err(|e| CodecError::Encode(e.into()))
+    }
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        rmp_serde::from_slice(bytes).map_err(|e| CodecError::Decode(e.into()))
+    }
+}
+
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    const CONTENT_TYPE: ContentType = ContentType::Cbor;
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, CodecError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes).map_err(|e| CodecError::Encode(e.into()))?;
+        Ok(bytes)
+    }
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        ciborium::from_reader(bytes).map_err(|e| CodecError::Decode(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{client::Client, server::Server};
+
+    #[derive(Deserialize, Serialize, Diff, Debug, Clone, Hash, PartialEq, Default)]
+    #[diff(attr(#[derive(Serialize, Deserialize)]))]
+    struct Data {
+        values: BTreeMap<u32, String>,
+        counter: u64,
+    }
+
+    const ENABLED: &[ContentType] = &[
+        #[cfg(feature = "json")]
+        ContentType::Json,
+        #[cfg(feature = "bincode")]
+        ContentType::Bincode,
+        #[cfg(feature = "postcard")]
+        ContentType::Postcard,
+        #[cfg(feature = "msgpack")]
+        ContentType::MessagePack,
+        #[cfg(feature = "cbor")]
+        ContentType::Cbor,
+    ];
+
+    #[test]
+    fn replaced_files_leave_no_temporary_files() {
+        let dir = std::env::temp_dir().join(format!("diffsync-replace-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("saved");
+        replace_file(&path, |file| file.write_all(b"first")).unwrap();
+        replace_file(&path, |file| file.write_all(b"second")).unwrap();
+        // a failed write keeps the previous file
+        let failed = replace_file(&path, |file| {
+            file.write_all(b"third")?;
+            Err(io::ErrorKind::Other.into())
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn round_trip_enabled_codecs() {
+        let mut server: Server<Data, u32> = Server::default();
+        for i in 0..10 {
+            server.state.values.insert(i, format!("{i}"));
+        }
+        server.state.counter = u64::MAX - 1;
+
+        for &content_type in ENABLED {
+            let client: Client<Data, u32> = Client::with_id(1);
+            let request = client.update_request();
+            let frame = encode_frame(content_type, &request).unwrap();
+            let (decoded_type, decoded, consumed) = decode_frame(&frame).unwrap().unwrap();
+            assert_eq!(decoded_type, content_type);
+            assert_eq!(consumed, frame.len());
+            assert_eq!(request, decoded);
+
+            let update = server.get_client_diff(request);
+            let bytes = content_type.encode(&update).unwrap();
+            let decoded: ClientUpdate<<Data as Diff>::Repr> = content_type.decode(&bytes).unwrap();
+            assert_eq!(update.newhash(), decoded.newhash());
+            let mut client: Client<Data, u32> = Client::with_id(1);
+            client.apply_update(decoded).unwrap();
+            assert_eq!(client.state, server.state);
+        }
+    }
+
+    #[test]
+    fn partial_frames() {
+        let Some(&content_type) = ENABLED.first() else {
+            return;
+        };
+        let mut buf = encode_frame(content_type, "first").unwrap();
+        buf.extend(encode_frame(content_type, "second").unwrap());
+
+        for cut in 0..FRAME_HEADER_LEN + 1 {
+            assert!(decode_frame::<String>(&buf[..cut]).unwrap().is_none());
+        }
+        let (_, first, consumed) = decode_frame::<String>(&buf).unwrap().unwrap();
+        assert_eq!(first, "first");
+        let (_, second, _) = decode_frame::<String>(&buf[consumed..]).unwrap().unwrap();
+        assert_eq!(second, "second");
+
+        assert!(decode_frame::<String>(&[0, 0, 0, 1, content_type.tag()]).is_err());
+        let oversized = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes();
+        assert!(matches!(
+            decode_frame::<String>(&oversized),
+            Err(CodecError::FrameTooLarge(_))
+        ));
+    }
+}
@@ -0,0 +1,213 @@
+//! Optional compression of encoded messages, applied by the framing in [`crate::codec`] after
+//! serialization. Every frame carries the [`Compression`] it was compressed with, so the
+//! receiving side only needs the feature of the algorithm enabled.
+
+use std::{borrow::Cow, io::Read};
+
+use crate::codec::{CodecError, MAX_FRAME_LEN};
+
+/// Compression algorithms, the discriminant is the flag used in frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[repr(u8)]
+pub enum Compression {
+    #[default]
+    None = 0,
+    /// zstd, needs the `zstd` feature
+    Zstd = 1,
+    /// lz4 frames, needs the `lz4` feature
+    Lz4 = 2,
+}
+
+impl Compression {
+    pub fn tag(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_tag(tag: u8) -> Result<Self, CodecError> {
+        Ok(match tag {
+            0 => Self::None,
+            1 => Self::Zstd,
+            2 => Self::Lz4,
+            tag => return Err(CodecError::UnknownCompression(tag)),
+        })
+    }
+
+    /// Compress the bytes, fails if the feature of the algorithm is not enabled
+    pub fn compress(self, bytes: &[u8]) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Self::None => Ok(bytes.to_vec()),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::bulk::compress(bytes, zstd::DEFAULT_COMPRESSION_LEVEL)
+                .map_err(|e| CodecError::Encode(e.into())),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => {
+                use std::io::Write;
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                encoder
+                    .write_all(bytes)
+                    .map_err(|e| CodecError::Encode(e.into()))?;
+                encoder.finish().map_err(|e| CodecError::Encode(e.into()))
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(CodecError::UnsupportedCompression(self)),
+        }
+    }
+
+    /// Decompress the bytes, failing instead of producing more than `max_len` bytes
+    pub fn decompress(self, bytes: &[u8], max_len: usize) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Self::None => Ok(bytes.to_vec()),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => read_limited(
+                zstd::stream::read::Decoder::with_buffer(bytes)
+                    .map_err(|e| CodecError::Decode(e.into()))?,
+                max_len,
+            ),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => read_limited(lz4_flex::frame::FrameDecoder::new(bytes), max_len),
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = (bytes, max_len);
+                Err(CodecError::UnsupportedCompression(self))
+            }
+        }
+    }
+}
+
+/// Read everything from a decompressing reader, without ever holding more than `max_len` bytes
+#[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(dead_code))]
+fn read_limited<R: Read>(reader: R, max_len: usize) -> Result<Vec<u8>, CodecError> {
+    let mut out = Vec::new();
+    reader
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|e| CodecError::Decode(e.into()))?;
+    if out.len() > max_len {
+        return Err(CodecError::DecompressedTooLarge(max_len));
+    }
+    Ok(out)
+}
+
+/// When and how frames are compressed, and how large they may become when decompressed
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    algorithm: Compression,
+    threshold: usize,
+    max_decompressed_len: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: Compression::None,
+            threshold: 16 * 1024,
+            max_decompressed_len: MAX_FRAME_LEN,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Compress with the algorithm, any frames received are decompressed regardless
+    pub fn new(algorithm: Compression) -> Self {
+        Self {
+            algorithm,
+            ..Default::default()
+        }
+    }
+
+    /// Only compress messages that are larger than this when encoded, defaults to 16 KiB
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Refuse to decompress received messages larger than this, defaults to
+    /// [`MAX_FRAME_LEN`]
+    pub fn with_max_decompressed_len(mut self, max_len: usize) -> Self {
+        self.max_decompressed_len = max_len;
+        self
+    }
+
+    pub fn algorithm(&self) -> Compression {
+        self.algorithm
+    }
+
+    pub fn max_decompressed_len(&self) -> usize {
+        self.max_decompressed_len
+    }
+
+    /// Compress an encoded message if it is above the threshold, returning the algorithm
+    /// actually used
+    pub fn compress(&self, bytes: Vec<u8>) -> Result<(Compression, Vec<u8>), CodecError> {
+        if self.algorithm == Compression::None || bytes.len() <= self.threshold {
+            return Ok((Compression::None, bytes));
+        }
+        Ok((self.algorithm, self.algorithm.compress(&bytes)?))
+    }
+
+    /// Decompress a received message with the algorithm from its frame
+    pub fn decompress<'a>(
+        &self,
+        compression: Compression,
+        bytes: &'a [u8],
+    ) -> Result<Cow<'a, [u8]>, CodecError> {
+        if compression != Compression::None {
+            return Ok(Cow::Owned(
+                compression.decompress(bytes, self.max_decompressed_len)?,
+            ));
+        }
+        if bytes.len() > self.max_decompressed_len {
+            return Err(CodecError::DecompressedTooLarge(self.max_decompressed_len));
+        }
+        Ok(Cow::Borrowed(bytes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ENABLED: &[Compression] = &[
+        #[cfg(feature = "zstd")]
+        Compression::Zstd,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4,
+    ];
+
+    #[test]
+    fn threshold_and_limit() {
+        let small = vec![7; 100];
+        let large = vec![7; 100_000];
+        for &algorithm in ENABLED {
+            let config = CompressionConfig::new(algorithm).with_threshold(1000);
+            assert_eq!(
+                config.compress(small.clone()).unwrap(),
+                (Compression::None, small.clone())
+            );
+
+            let (used, compressed) = config.compress(large.clone()).unwrap();
+            assert_eq!(used, algorithm);
+            assert!(compressed.len() < large.len() / 10);
+            assert_eq!(*config.decompress(used, &compressed).unwrap(), *large);
+
+            let limited = config.with_max_decompressed_len(large.len() - 1);
+            assert!(matches!(
+                limited.decompress(used, &compressed),
+                Err(CodecError::DecompressedTooLarge(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn disabled_algorithms_are_reported() {
+        for algorithm in [Compression::Zstd, Compression::Lz4] {
+            if !ENABLED.contains(&algorithm) {
+                assert!(matches!(
+                    algorithm.decompress(&[], 10),
+                    Err(CodecError::UnsupportedCompression(_))
+                ));
+            }
+        }
+        assert!(Compression::from_tag(9).is_err());
+    }
+}
@@ -1,19 +1,352 @@
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fmt::Debug,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+use dashmap::{
+    iter::Iter,
+    mapref::{entry::Entry, one::Ref},
+};
+
 use super::*;
-use crate::structs::SimpleDiffTrait;
+use crate::{customhash::XxHash64, structs::SimpleDiffTrait};
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct ConcMap<K: Eq + Ord + Hash, V: PartialEq>(pub DashMap<K, V>);
+/// Number of changed keys a map remembers by default
+pub const DEFAULT_JOURNAL_LEN: usize = 4096;
+
+/// A concurrent map, that keeps an order independent digest of its entries up to date on every
+/// change, so that hashing it does not have to visit the entries.
+///
+/// It also keeps a journal of the keys changed recently, so that diffing a map against an
+/// earlier clone of itself only has to look at those keys. Clones further behind than the
+/// journal reaches, or that have been changed themselves, are diffed by comparing every entry.
+///
+/// The entries are hashed into the digest with `H`, which should be the algorithm of the
+/// [`crate::server::Server`] and clients, so that the digest is as strong as their hashes.
+pub struct ConcMap<K: Eq + Ord + Hash, V: PartialEq, H = XxHash64> {
+    map: DashMap<K, V>,
+    digest: Digest,
+    journal: Mutex<Journal<K>>,
+    hasher: PhantomData<fn() -> H>,
+}
 
-impl<K: Eq + Ord + Hash, V: PartialEq> Default for ConcMap<K, V> {
+impl<K: Eq + Ord + Hash, V: PartialEq, H> Default for ConcMap<K, V, H> {
     fn default() -> Self {
-        Self(DashMap::default())
+        Self {
+            map: DashMap::default(),
+            digest: Digest::default(),
+            journal: Mutex::new(Journal::new(DEFAULT_JOURNAL_LEN)),
+            hasher: PhantomData,
+        }
+    }
+}
+
+impl<K: Eq + Ord + Hash + Clone, V: PartialEq + Hash> ConcMap<K, V> {
+    /// A map hashing with the default algorithm, other algorithms are picked with
+    /// [`Default::default`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K: Eq + Ord + Hash + Clone, V: PartialEq + Hash, H: HashAlgorithm> ConcMap<K, V, H> {
+    /// Set how many changed keys are remembered for diffing
+    pub fn with_journal_len(self, len: usize) -> Self {
+        self.lock_journal().set_max_len(len);
+        self
+    }
+
+    /// Insert a value, returning the one it replaced
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.digest.add(entry_hash::<H, _, _>(&key, &value));
+        // the journal is written after the map, see `Clone`
+        let (old, key) = match self.map.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let old = entry.insert(value);
+                self.digest.sub(entry_hash::<H, _, _>(entry.key(), &old));
+                (Some(old), entry.key().clone())
+            }
+            Entry::Vacant(entry) => {
+                let key = entry.key().clone();
+                entry.insert(value);
+                (None, key)
+            }
+        };
+        self.lock_journal().record(key);
+        old
+    }
+
+    /// Remove a key, returning the entry
+    pub fn remove(&self, key: &K) -> Option<(K, V)> {
+        let removed = self.map.remove(key)?;
+        self.digest
+            .sub(entry_hash::<H, _, _>(&removed.0, &removed.1));
+        self.lock_journal().record(removed.0.clone());
+        Some(removed)
+    }
+
+    /// Only keep the entries the predicate returns true for
+    pub fn retain(&self, mut keep: impl FnMut(&K, &V) -> bool) {
+        self.map.retain(|key, value| {
+            let kept = keep(key, value);
+            if !kept {
+                self.digest.sub(entry_hash::<H, _, _>(key, value));
+                // the shard stays locked until the entry is gone, so this is after the map too
+                self.lock_journal().record(key.clone());
+            }
+            kept
+        });
+    }
+
+    pub fn clear(&self) {
+        self.retain(|_, _| false);
+    }
+}
+
+impl<K: Eq + Ord + Hash, V: PartialEq, H> ConcMap<K, V, H> {
+    fn lock_journal(&self) -> std::sync::MutexGuard<'_, Journal<K>> {
+        self.journal.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, key: &K) -> Option<Ref<'_, K, V>> {
+        self.map.get(key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Iterate over the entries, in no particular order
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        self.map.iter()
+    }
+
+    /// The map holding the entries. Changes have to go through the [`ConcMap`] to keep its
+    /// digest and journal up to date, so this is only for reading
+    pub fn inner(&self) -> &DashMap<K, V> {
+        &self.map
+    }
+
+    /// Take the entries out of the map
+    pub fn into_inner(self) -> DashMap<K, V> {
+        self.map
+    }
+}
+
+impl<'a, K: Eq + Ord + Hash, V: PartialEq, H> IntoIterator for &'a ConcMap<K, V, H> {
+    type Item = <&'a DashMap<K, V> as IntoIterator>::Item;
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: Eq + Ord + Hash + Clone, V: PartialEq + Hash, H: HashAlgorithm> FromIterator<(K, V)>
+    for ConcMap<K, V, H>
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let map = Self::default();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+impl<K: Eq + Ord + Hash, V: PartialEq + Hash, H: HashAlgorithm> From<DashMap<K, V>>
+    for ConcMap<K, V, H>
+{
+    fn from(map: DashMap<K, V>) -> Self {
+        let digest = Digest::default();
+        map.iter()
+            .for_each(|r| digest.add(entry_hash::<H, _, _>(r.key(), r.value())));
+        Self {
+            map,
+            digest,
+            ..Default::default()
+        }
+    }
+}
+
+impl<K: Eq + Ord + Hash + Clone, V: PartialEq + Hash + Clone, H: HashAlgorithm> Clone
+    for ConcMap<K, V, H>
+{
+    fn clone(&self) -> Self {
+        // Changes are written to the map before the journal, so copying the journal first
+        // guarantees that every change it lists is in the copied map. The digest is rebuilt,
+        // as copying it could race with changes to the map
+        let journal = self.lock_journal().clone();
+        let mut clone = Self::from(self.map.clone());
+        clone.journal = Mutex::new(journal);
+        clone
     }
 }
 
-impl<K: Clone + Ord + Hash, V: PartialEq + Clone> ConcMap<K, V> {
-    fn as_btree(&self) -> BTreeMap<K, V> {
+impl<K: Eq + Ord + Hash + Debug, V: PartialEq + Debug, H> Debug for ConcMap<K, V, H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.map.fmt(f)
+    }
+}
+
+impl<K: Eq + Ord + Hash + Serialize, V: PartialEq + Serialize, H> Serialize for ConcMap<K, V, H> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.map.serialize(serializer)
+    }
+}
+
+impl<'de, K, V, H> Deserialize<'de> for ConcMap<K, V, H>
+where
+    K: Eq + Ord + Hash + Deserialize<'de>,
+    V: PartialEq + Hash + Deserialize<'de>,
+    H: HashAlgorithm,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        DashMap::deserialize(deserializer).map(Self::from)
+    }
+}
+
+/// Keys changed, stamped with a number unique across all maps, so that a stamp identifies
+/// both a map and a version of it
+#[derive(Clone)]
+struct Journal<K> {
+    /// Stamp of the version before the oldest change still in `changes`
+    base: u64,
+    changes: VecDeque<(u64, K)>,
+    max_len: usize,
+}
+
+static NEXT_STAMP: AtomicU64 = AtomicU64::new(0);
+
+fn next_stamp() -> u64 {
+    NEXT_STAMP.fetch_add(1, Ordering::Relaxed)
+}
+
+impl<K> Journal<K> {
+    fn new(max_len: usize) -> Self {
+        Self {
+            base: next_stamp(),
+            changes: VecDeque::new(),
+            max_len,
+        }
+    }
+
+    fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+        self.truncate();
+    }
+
+    fn truncate(&mut self) {
+        while self.changes.len() > self.max_len {
+            if let Some((stamp, _)) = self.changes.pop_front() {
+                self.base = stamp;
+            }
+        }
+    }
+
+    /// The stamp of the current version
+    fn stamp(&self) -> u64 {
+        self.changes.back().map_or(self.base, |(stamp, _)| *stamp)
+    }
+
+    fn record(&mut self, key: K) {
+        // taken while locked, so stamps are increasing within the journal
+        self.changes.push_back((next_stamp(), key));
+        self.truncate();
+    }
+}
+
+impl<K: Ord + Clone> Journal<K> {
+    /// Keys changed since the version with the stamp, if it is a version of this map that is
+    /// still covered
+    fn changed_since(&self, stamp: u64) -> Option<BTreeSet<K>> {
+        let after = if stamp == self.base {
+            0
+        } else {
+            self.changes
+                .binary_search_by_key(&stamp, |(stamp, _)| *stamp)
+                .ok()?
+                + 1
+        };
+        Some(
+            self.changes
+                .range(after..)
+                .map(|(_, key)| key.clone())
+                .collect(),
+        )
+    }
+}
+
+/// Wrapping sum of the hashes of every entry, in two independent 64 bit lanes
+#[derive(Default)]
+struct Digest([AtomicU64; 2]);
+
+impl Digest {
+    fn add(&self, hash: [u64; 2]) {
+        for (lane, hash) in self.0.iter().zip(hash) {
+            lane.fetch_add(hash, Ordering::Relaxed);
+        }
+    }
+
+    fn sub(&self, hash: [u64; 2]) {
+        for (lane, hash) in self.0.iter().zip(hash) {
+            lane.fetch_sub(hash, Ordering::Relaxed);
+        }
+    }
+
+    fn get(&self) -> [u64; 2] {
+        [
+            self.0[0].load(Ordering::Relaxed),
+            self.0[1].load(Ordering::Relaxed),
+        ]
+    }
+}
+
+/// Hash of a single entry, split into the lanes of the digest. 64 bit algorithms leave the
+/// second lane at zero
+fn entry_hash<H: HashAlgorithm, K: Hash, V: Hash>(key: &K, value: &V) -> [u64; 2] {
+    let hash = H::hash_of(&(key, value));
+    [hash as u64, (hash >> 64) as u64]
+}
+
+impl<K: Clone + Ord + Hash, V: PartialEq + Hash + Clone, H: HashAlgorithm> ConcMap<K, V, H> {
+    /// The changes from `old` to this map, if `old` is an unchanged clone of an earlier
+    /// version of it that the journal still covers
+    pub fn changes_since(&self, old: &Self) -> Option<SimpleDiff<K, V>> {
+        let stamp = old.lock_journal().stamp();
+        let keys = self.lock_journal().changed_since(stamp)?;
+        let mut diff = SimpleDiff::new();
+        for key in keys {
+            let old_value = old.get(&key);
+            match self.get(&key) {
+                Some(new_value) if old_value.as_deref() != Some(new_value.value()) => {
+                    diff.altered.insert(key, new_value.clone());
+                }
+                None if old_value.is_some() => {
+                    diff.removed.insert(key);
+                }
+                _ => {}
+            }
+        }
+        Some(diff)
+    }
+
+    pub(crate) fn as_btree(&self) -> BTreeMap<K, V> {
         let mut bmap = BTreeMap::new();
-        self.0.iter().for_each({
+        self.map.iter().for_each({
             |r| {
                 // map into btree, heavy operation unfortunately
                 let key = r.key().clone();
@@ -24,19 +357,54 @@ impl<K: Clone + Ord + Hash, V: PartialEq + Clone> ConcMap<K, V> {
 
         bmap
     }
+
+    /// Merge changes made by a client into the server map, both based on `base`. Keys changed
+    /// on only one side keep that change, keys changed on both sides are taken from the client
+    /// if `client_wins`, otherwise from the server
+    pub fn merge(
+        base: &Self,
+        server: &Self,
+        client_diff: &SimpleDiff<K, V>,
+        client_wins: bool,
+    ) -> Self {
+        let merged = server.clone();
+        let take_client = |key: &K| {
+            let server_value = server.get(key);
+            let base_value = base.get(key);
+            client_wins
+                || server_value.as_ref().map(|r| r.value())
+                    == base_value.as_ref().map(|r| r.value())
+        };
+        for key in &client_diff.removed {
+            if take_client(key) {
+                merged.remove(key);
+            }
+        }
+        for (key, value) in &client_diff.altered {
+            if take_client(key) {
+                merged.insert(key.clone(), value.clone());
+            }
+        }
+        merged
+    }
 }
 
-impl<K: Ord + Hash + Clone, V: PartialEq + Hash + Clone> Hash for ConcMap<K, V> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        let bmap = self.as_btree();
-        bmap.hash(state)
+impl<K: Ord + Hash + Clone, V: PartialEq + Hash + Clone, H> Hash for ConcMap<K, V, H> {
+    fn hash<S: Hasher>(&self, state: &mut S) {
+        self.len().hash(state);
+        self.digest.get().hash(state);
     }
 }
 
-impl<K: Ord + Hash + Clone, V: PartialEq + Hash + Clone> Diff for ConcMap<K, V> {
+impl<K: Ord + Hash + Clone, V: PartialEq + Hash + Clone, H: HashAlgorithm> Diff
+    for ConcMap<K, V, H>
+{
     type Repr = SimpleDiff<K, V>;
 
     fn diff(&self, other: &Self) -> Self::Repr {
+        if let Some(diff) = other.changes_since(self) {
+            return diff;
+        }
         let s = self.as_btree();
         let o = other.as_btree();
         SimpleDiff::generate(&s, &o)
@@ -44,14 +412,14 @@ impl<K: Ord + Hash + Clone, V: PartialEq + Hash + Clone> Diff for ConcMap<K, V>
 
     fn apply(&mut self, diff: &Self::Repr) {
         diff.removed.iter().for_each(|del| {
-            self.0.remove(del);
+            self.remove(del);
         });
         for (key, change) in &diff.altered {
-            self.0.insert(key.clone(), change.clone());
+            self.insert(key.clone(), change.clone());
         }
     }
 
     fn identity() -> Self {
-        Self(DashMap::new())
+        Self::default()
     }
 }
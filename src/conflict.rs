@@ -0,0 +1,217 @@
+use std::collections::BTreeSet;
+
+use super::*;
+
+/// Decides how changes pushed by a client are merged with changes made on the server since the
+/// version the client based its changes on.
+///
+/// The built in policies pick one side's whole state once both sides have changed: a push is
+/// applied as is while the server is still in the version the client based its changes on, and
+/// otherwise the winning side's state is taken. To keep the changes of both sides, merge the
+/// states value by value with [`ConflictPolicy::merge`] and a [`Merge`] implementation, use
+/// [`ConflictPolicy::last_writer_wins_per_key`] for maps diffed with a [`SimpleDiff`], or
+/// [`ConcMap::merge`] to build custom per key policies. Avoid applying the client's diff to the
+/// server state in a custom policy: the diffs derived by diff-struct store numbers as deltas,
+/// which would add up the changes of both sides to a value neither wrote.
+#[derive(Default)]
+pub enum ConflictPolicy<STATE: Diff> {
+    /// The push is applied if the server hasn't changed since the client's base, otherwise the
+    /// server state is kept
+    #[default]
+    ServerWins,
+    /// The pushed state replaces the server state, including the server's changes since the
+    /// client's base
+    ClientWins,
+    /// The state of whichever side wrote last is kept. The server side write time is when its
+    /// current version was committed, see [`crate::server::Server::commit`]
+    LastWriterWins,
+    /// Merge using a custom function, which returns the new server state
+    Custom(MergeFn<STATE>),
+}
+
+/// Custom merge function for [`ConflictPolicy::Custom`]
+pub type MergeFn<STATE> = Box<dyn Fn(&Conflict<'_, STATE>) -> STATE + Send + Sync>;
+
+impl<STATE: Diff> std::fmt::Debug for ConflictPolicy<STATE> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ServerWins => write!(f, "ServerWins"),
+            Self::ClientWins => write!(f, "ClientWins"),
+            Self::LastWriterWins => write!(f, "LastWriterWins"),
+            Self::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// Everything known about a push when it is merged into the server state
+pub struct Conflict<'a, STATE: Diff> {
+    /// The version the client based its changes on
+    pub base: &'a STATE,
+    /// Hash of `base`
+    pub base_hash: StateHash,
+    /// The current server state
+    pub server: &'a STATE,
+    /// Hash of `server`
+    pub server_hash: StateHash,
+    /// The state of the client, that is `base` with `client_diff` applied
+    pub pushed: &'a STATE,
+    /// The changes made by the client, relative to `base`
+    pub client_diff: &'a STATE::Repr,
+    /// When the client made the push, in milliseconds since the unix epoch
+    pub client_timestamp: u64,
+    /// When the current server state was committed, in milliseconds since the unix epoch
+    pub server_timestamp: u64,
+}
+
+impl<STATE: Diff> Conflict<'_, STATE> {
+    /// Whether the client wrote after the server, ties go to the client
+    pub fn client_is_newer(&self) -> bool {
+        self.client_timestamp >= self.server_timestamp
+    }
+}
+
+impl<STATE: Diff + Clone> ConflictPolicy<STATE> {
+    /// Merge the conflict into a new server state
+    pub fn resolve(&self, conflict: &Conflict<'_, STATE>) -> STATE {
+        let server_changed = conflict.base_hash != conflict.server_hash;
+        match self {
+            Self::ServerWins if server_changed => conflict.server.clone(),
+            Self::LastWriterWins if server_changed && !conflict.client_is_newer() => {
+                conflict.server.clone()
+            }
+            Self::ServerWins | Self::ClientWins | Self::LastWriterWins => conflict.pushed.clone(),
+            Self::Custom(merge) => merge(conflict),
+        }
+    }
+}
+
+impl<STATE: Diff + Clone + Merge + 'static> ConflictPolicy<STATE> {
+    /// Merge the pushed and the server state with [`Merge`], keeping what only one side changed.
+    /// Values both sides changed are taken from the client if `client_wins`, for example
+    /// `|conflict| conflict.client_is_newer()`, otherwise from the server
+    pub fn merge(client_wins: fn(&Conflict<'_, STATE>) -> bool) -> Self {
+        Self::Custom(Box::new(move |conflict| {
+            if client_wins(conflict) {
+                STATE::merge(conflict.base, conflict.pushed, conflict.server)
+            } else {
+                STATE::merge(conflict.base, conflict.server, conflict.pushed)
+            }
+        }))
+    }
+}
+
+/// Three way merge of two states derived from the same base, for [`ConflictPolicy::merge`].
+///
+/// Implementations keep whatever only one side changed, and take what both changed from
+/// `winner`. Values are taken whole, never by combining the changes of both sides. Structs
+/// usually merge field by field, see [`merge_value`] for the fields that are merged whole
+pub trait Merge {
+    fn merge(base: &Self, winner: &Self, other: &Self) -> Self;
+}
+
+/// Merge a value whole: `other` if only it changed, otherwise `winner`
+pub fn merge_value<T: PartialEq + Clone>(base: &T, winner: &T, other: &T) -> T {
+    if winner == base && other != base {
+        other.clone()
+    } else {
+        winner.clone()
+    }
+}
+
+macro_rules! merge_whole {
+    ($($ty:ty),*) => {
+        $(
+            impl Merge for $ty {
+                fn merge(base: &Self, winner: &Self, other: &Self) -> Self {
+                    merge_value(base, winner, other)
+                }
+            }
+        )*
+    };
+}
+
+merge_whole!(
+    bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, String
+);
+
+impl<T: PartialEq + Clone> Merge for Option<T> {
+    fn merge(base: &Self, winner: &Self, other: &Self) -> Self {
+        merge_value(base, winner, other)
+    }
+}
+
+/// Merged key by key, with the values taken whole
+impl<K: Ord + Clone, V: PartialEq + Clone> Merge for BTreeMap<K, V> {
+    fn merge(base: &Self, winner: &Self, other: &Self) -> Self {
+        let mut merged = winner.clone();
+        for key in base.keys().chain(other.keys()) {
+            let value = |map: &Self| map.get(key).cloned();
+            match merge_value(&value(base), &value(winner), &value(other)) {
+                Some(value) => merged.insert(key.clone(), value),
+                None => merged.remove(key),
+            };
+        }
+        merged
+    }
+}
+
+/// Merged key by key, with the values taken whole
+impl<K, V, H> Merge for ConcMap<K, V, H>
+where
+    K: Ord + Hash + Clone,
+    V: PartialEq + Hash + Clone,
+    H: HashAlgorithm,
+{
+    fn merge(base: &Self, winner: &Self, other: &Self) -> Self {
+        let merged = winner.clone();
+        let value = |map: &Self, key: &K| map.get(key).map(|r| r.value().clone());
+        let keys: BTreeSet<K> = base
+            .iter()
+            .chain(other.iter())
+            .map(|r| r.key().clone())
+            .collect();
+        for key in keys {
+            match merge_value(
+                &value(base, &key),
+                &value(winner, &key),
+                &value(other, &key),
+            ) {
+                Some(value) => {
+                    merged.insert(key, value);
+                }
+                None => {
+                    merged.remove(&key);
+                }
+            }
+        }
+        merged
+    }
+}
+
+impl<K, V, H> ConflictPolicy<ConcMap<K, V, H>>
+where
+    K: Ord + Hash + Clone + Send + Sync + 'static,
+    V: PartialEq + Hash + Clone + Send + Sync + 'static,
+    H: HashAlgorithm + 'static,
+{
+    /// Keep the changes of both sides, and let the side that wrote last win for keys changed
+    /// by both
+    pub fn last_writer_wins_per_key() -> Self {
+        Self::Custom(Box::new(|conflict| {
+            ConcMap::merge(
+                conflict.base,
+                conflict.server,
+                conflict.client_diff,
+                conflict.client_is_newer(),
+            )
+        }))
+    }
+}
+
+/// Milliseconds since the unix epoch
+pub(crate) fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
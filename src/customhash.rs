@@ -1,29 +1,463 @@
-use std::hash::Hasher;
+use std::hash::{Hash, Hasher};
 
-use twox_hash::XxHash64;
-pub struct CustomHash(twox_hash::XxHash64);
+use serde::{Deserialize, Serialize};
 
-impl CustomHash {
+/// A state hash. Every algorithm produces one of these, 64 bit algorithms use the low half
+pub type StateHash = u128;
+
+/// Serde helpers for [`StateHash`] fields of the protocol messages. Human readable formats like
+/// JSON get the hash as a hex string, the way the `http` module writes it in ETags, since readers
+/// like browsers turn large numbers into floats. Other formats get the number.
+///
+/// This is a breaking change of the wire format since 0.1, for every human readable format and
+/// not just the transports of this crate: a 0.1 peer can't read the hashes written as strings.
+/// Hashes written as numbers by a 0.1 peer are still read, so servers can be upgraded before
+/// their clients
+pub mod hex {
+    use std::{collections::BTreeMap, fmt};
+
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::StateHash;
+
+    pub fn serialize<S: Serializer>(hash: &StateHash, serializer: S) -> Result<S::Ok, S::Error> {
+        Hex(*hash).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<StateHash, D::Error> {
+        Hex::deserialize(deserializer).map(|hex| hex.0)
+    }
+
+    /// The same for maps with hashes as values
+    pub mod map {
+        use super::*;
+
+        pub fn serialize<K, S>(
+            map: &BTreeMap<K, StateHash>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error>
+        where
+            K: Serialize,
+            S: Serializer,
+        {
+            serializer.collect_map(map.iter().map(|(key, hash)| (key, Hex(*hash))))
+        }
+
+        pub fn deserialize<'de, K, D>(deserializer: D) -> Result<BTreeMap<K, StateHash>, D::Error>
+        where
+            K: Deserialize<'de> + Ord,
+            D: Deserializer<'de>,
+        {
+            let map = BTreeMap::<K, Hex>::deserialize(deserializer)?;
+            Ok(map.into_iter().map(|(key, hex)| (key, hex.0)).collect())
+        }
+    }
+
+    struct Hex(StateHash);
+
+    impl Serialize for Hex {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            if serializer.is_human_readable() {
+                serializer.collect_str(&format_args!("{:032x}", self.0))
+            } else {
+                serializer.serialize_u128(self.0)
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Hex {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            if deserializer.is_human_readable() {
+                deserializer.deserialize_any(HexVisitor)
+            } else {
+                StateHash::deserialize(deserializer).map(Hex)
+            }
+        }
+    }
+
+    struct HexVisitor;
+
+    impl de::Visitor<'_> for HexVisitor {
+        type Value = Hex;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a state hash as a hex string")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Hex, E> {
+            StateHash::from_str_radix(v, 16)
+                .map(Hex)
+                .map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+        }
+
+        // hashes written as numbers are still read, they are exact if they were small enough
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<Hex, E> {
+            Ok(Hex(v.into()))
+        }
+
+        fn visit_u128<E: de::Error>(self, v: u128) -> Result<Hex, E> {
+            Ok(Hex(v))
+        }
+    }
+}
+
+/// Which algorithm a hash was made with, sent along with hashes so that a client and server
+/// configured with different algorithms notice instead of never agreeing on anything
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HashKind {
+    #[default]
+    XxHash64,
+    XxHash3,
+    Blake3,
+    SipHash,
+}
+
+impl HashKind {
+    /// Width of the hashes in bits
+    pub fn bits(self) -> u32 {
+        match self {
+            Self::XxHash64 => 64,
+            Self::XxHash3 | Self::Blake3 | Self::SipHash => 128,
+        }
+    }
+}
+
+/// A hash algorithm for states, shared by a [`crate::client::Client`] and
+/// [`crate::server::Server`] through their type parameter. The algorithm only sees bytes,
+/// [`CustomHash`] takes care of turning values into bytes the same way on every platform
+pub trait HashAlgorithm: Default {
+    const KIND: HashKind;
+
+    fn write(&mut self, bytes: &[u8]);
+    fn finish(&self) -> StateHash;
+
+    /// Hash a value the same way the client and server hash their states
+    fn hash_of<T: Hash + ?Sized>(value: &T) -> StateHash {
+        let mut h = CustomHash::<Self>::default();
+        value.hash(&mut h);
+        h.finish128()
+    }
+}
+
+/// XxHash64 with seed 1337, the default
+#[derive(Clone)]
+pub struct XxHash64(twox_hash::XxHash64);
+
+impl Default for XxHash64 {
+    fn default() -> Self {
+        Self(twox_hash::XxHash64::with_seed(1337))
+    }
+}
+
+impl HashAlgorithm for XxHash64 {
+    const KIND: HashKind = HashKind::XxHash64;
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes)
+    }
+    fn finish(&self) -> StateHash {
+        self.0.finish().into()
+    }
+}
+
+/// The 128 bit variant of XXH3
+#[cfg(feature = "xxh3")]
+#[derive(Clone, Default)]
+pub struct XxHash3(xxhash_rust::xxh3::Xxh3);
+
+#[cfg(feature = "xxh3")]
+impl HashAlgorithm for XxHash3 {
+    const KIND: HashKind = HashKind::XxHash3;
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes)
+    }
+    fn finish(&self) -> StateHash {
+        self.0.digest128()
+    }
+}
+
+/// BLAKE3, truncated to 128 bits
+#[cfg(feature = "blake3")]
+#[derive(Clone, Default)]
+pub struct Blake3(blake3::Hasher);
+
+#[cfg(feature = "blake3")]
+impl HashAlgorithm for Blake3 {
+    const KIND: HashKind = HashKind::Blake3;
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+    fn finish(&self) -> StateHash {
+        let hash = self.0.finalize();
+        let (low, _) = hash.as_bytes().split_at(16);
+        u128::from_le_bytes(low.try_into().expect("split at 16"))
+    }
+}
+
+/// SipHash-1-3 with 128 bit output and zero keys
+#[cfg(feature = "siphash")]
+#[derive(Clone, Default)]
+pub struct SipHash(siphasher::sip128::SipHasher13);
+
+#[cfg(feature = "siphash")]
+impl HashAlgorithm for SipHash {
+    const KIND: HashKind = HashKind::SipHash;
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes)
+    }
+    fn finish(&self) -> StateHash {
+        use siphasher::sip128::Hasher128;
+        self.0.finish128().as_u128()
+    }
+}
+
+/// The [`Hasher`] used for the state hashes that clients and servers compare.
+///
+/// The hash has to be the same on 32 and 64 bit platforms, so integers are always written little
+/// endian, and `usize`/`isize` (which lengths of collections are written as) are always written
+/// as 64 bit integers, whatever the width of the platform.
+///
+/// [`Hash`] for a slice of integers, which `Vec`s and arrays hash as, writes its length and then
+/// hands the memory of the whole slice to [`Hasher::write`], with `usize` as wide as the
+/// platform. So bytes written right after a `usize` that are 4 bytes for each counted element
+/// are taken to be 32 bit elements, and widened to 64 bits, the way a 64 bit platform writes a
+/// slice of `usize`. 32 bit integers of other types are widened the same on every platform, so
+/// only negative `isize`s in slices hash differently. The memory of slices is written in the
+/// byte order of the platform, so slices of integers wider than a byte still hash differently
+/// on big endian platforms.
+pub struct CustomHash<A: HashAlgorithm = XxHash64> {
+    algorithm: A,
+    /// The last value written, if it was a `usize`, which may be the length of the slice
+    /// written next
+    len: Option<u64>,
+}
+
+impl<A: HashAlgorithm> Default for CustomHash<A> {
+    fn default() -> Self {
+        Self::with_algorithm(A::default())
+    }
+}
+
+impl CustomHash<XxHash64> {
     pub fn new() -> Self {
-        let h = XxHash64::with_seed(1337);
-        Self(h)
+        Self::default()
     }
     pub fn with_seed(seed: u64) -> Self {
-        let h = XxHash64::with_seed(seed);
-        Self(h)
+        Self::with_algorithm(XxHash64(twox_hash::XxHash64::with_seed(seed)))
     }
 }
 
-impl Hasher for CustomHash {
+impl<A: HashAlgorithm> CustomHash<A> {
+    fn with_algorithm(algorithm: A) -> Self {
+        Self {
+            algorithm,
+            len: None,
+        }
+    }
+
+    /// The full width hash, [`Hasher::finish`] only gives the low 64 bits
+    pub fn finish128(&self) -> StateHash {
+        self.algorithm.finish()
+    }
+
+    /// Write the bytes of an integer, which is never the memory of a slice
+    fn write_integer(&mut self, bytes: &[u8]) {
+        self.len = None;
+        self.algorithm.write(bytes)
+    }
+}
+
+/// Bytes of widened 32 bit elements written at once
+const WIDENED_CHUNK: usize = 256;
+
+impl<A: HashAlgorithm> Hasher for CustomHash<A> {
     fn finish(&self) -> u64 {
-        self.0.finish()
+        self.algorithm.finish() as u64
     }
 
     fn write(&mut self, bytes: &[u8]) {
-        self.0.write(bytes)
+        let widen = matches!(
+            self.len.take(),
+            Some(len) if len != 0 && len.checked_mul(4) == Some(bytes.len() as u64)
+        );
+        if !widen {
+            return self.algorithm.write(bytes);
+        }
+        let mut widened = [0; WIDENED_CHUNK];
+        for elements in bytes.chunks(WIDENED_CHUNK / 2) {
+            for (element, wide) in elements.chunks(4).zip(widened.chunks_mut(8)) {
+                wide[..4].copy_from_slice(element);
+            }
+            self.algorithm.write(&widened[..elements.len() * 2]);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.write_integer(&[i])
+    }
+    fn write_u16(&mut self, i: u16) {
+        self.write_integer(&i.to_le_bytes())
+    }
+    fn write_u32(&mut self, i: u32) {
+        self.write_integer(&i.to_le_bytes())
     }
-    // yeah, lets not, it messes with hashing of BTree on different platforms
-    fn write_length_prefix(&mut self, _len: usize) {
-        // self.write_u64(len as u64);
+    fn write_u64(&mut self, i: u64) {
+        self.write_integer(&i.to_le_bytes())
+    }
+    fn write_u128(&mut self, i: u128) {
+        self.write_integer(&i.to_le_bytes())
+    }
+    // also used for the length prefix of collections, so it has to be the same on 32 and 64 bit
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+        self.len = Some(i as u64);
+    }
+
+    fn write_i8(&mut self, i: i8) {
+        self.write_u8(i as u8)
+    }
+    fn write_i16(&mut self, i: i16) {
+        self.write_u16(i as u16)
+    }
+    fn write_i32(&mut self, i: i32) {
+        self.write_u32(i as u32)
+    }
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64)
+    }
+    fn write_i128(&mut self, i: i128) {
+        self.write_u128(i as u128)
+    }
+    fn write_isize(&mut self, i: isize) {
+        self.write_i64(i as i64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn hash_with(write: impl FnOnce(&mut CustomHash)) -> StateHash {
+        let mut h = CustomHash::new();
+        write(&mut h);
+        h.finish128()
+    }
+
+    #[test]
+    fn integers_are_written_with_fixed_width_and_endianness() {
+        assert_eq!(
+            hash_with(|h| h.write_usize(0x0102_0304)),
+            hash_with(|h| h.write(&[4, 3, 2, 1, 0, 0, 0, 0]))
+        );
+        assert_eq!(
+            hash_with(|h| h.write_isize(-2)),
+            hash_with(|h| h.write_i64(-2))
+        );
+        assert_eq!(
+            hash_with(|h| h.write_u32(0x0102_0304)),
+            hash_with(|h| h.write(&[4, 3, 2, 1]))
+        );
+        assert_eq!(
+            hash_with(|h| h.write_i16(-1)),
+            hash_with(|h| h.write(&[0xff, 0xff]))
+        );
+    }
+
+    #[test]
+    fn lengths_hash_as_64_bit_on_any_platform() {
+        // the length of a collection comes first as a u64, 32 bit platforms included
+        let expected = hash_with(|h| {
+            h.write(&2u64.to_le_bytes());
+            h.write(&[1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+        });
+        assert_eq!(XxHash64::hash_of(&vec![1u32, 2]), expected);
+        assert_eq!(XxHash64::hash_of(&[1u64, 2]), expected);
+    }
+
+    #[test]
+    fn usize_slices_hash_as_on_a_64_bit_platform() {
+        let values: Vec<usize> = vec![0x0102_0304, 7];
+        // what a 32 bit platform writes for the slice
+        let narrow = hash_with(|h| {
+            h.write_usize(2);
+            h.write(&[4, 3, 2, 1, 7, 0, 0, 0]);
+        });
+        // and what a 64 bit one writes
+        let wide = hash_with(|h| {
+            h.write_usize(2);
+            h.write(&[4, 3, 2, 1, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0]);
+        });
+        assert_eq!(narrow, wide);
+        assert_eq!(XxHash64::hash_of(&values), wide);
+
+        // long slices are widened in chunks
+        let long: Vec<usize> = (0..1000).collect();
+        let long32: Vec<u32> = (0..1000).collect();
+        assert_eq!(XxHash64::hash_of(&long), XxHash64::hash_of(&long32));
+    }
+
+    #[test]
+    fn structs_hash_the_same_on_any_platform() {
+        #[derive(Hash)]
+        struct Tag {
+            count: usize,
+            offsets: Vec<usize>,
+            ids: Vec<u32>,
+            delta: isize,
+            name: String,
+        }
+        let tag = Tag {
+            count: 3,
+            offsets: vec![1, 1 << 20, 3],
+            ids: vec![7, 8],
+            delta: -5,
+            name: "anchor".into(),
+        };
+        // what a 32 bit platform writes for it
+        let narrow = hash_with(|h| {
+            h.write_usize(3);
+            h.write_usize(3);
+            h.write(&[1, 0, 0, 0, 0, 0, 0x10, 0, 3, 0, 0, 0]);
+            h.write_usize(2);
+            h.write(&[7, 0, 0, 0, 8, 0, 0, 0]);
+            h.write_isize(-5);
+            h.write(b"anchor");
+            h.write_u8(0xff);
+        });
+        assert_eq!(XxHash64::hash_of(&tag), narrow);
+        // and this must never change either
+        assert_eq!(XxHash64::hash_of(&tag), 0xc5c5_d469_73a7_a3e0);
+    }
+
+    #[test]
+    fn golden_hashes() {
+        // these must never change, or clients and servers of different versions disagree
+        assert_eq!(XxHash64::hash_of(&0u64), 0xd9fe_26aa_59b2_3cab);
+        let map: BTreeMap<u32, String> = [(1, "one".into()), (2, "two".into())].into();
+        assert_eq!(XxHash64::hash_of(&map), 0x46fa_4dd8_9daf_b739);
+        assert_eq!(XxHash64::hash_of(&(-1isize, 7usize)), 0x4ec4_b689_d228_392c);
+    }
+
+    #[test]
+    fn wide_golden_hashes() {
+        let map: BTreeMap<u32, String> = [(1, "one".into()), (2, "two".into())].into();
+        #[cfg(feature = "xxh3")]
+        assert_eq!(
+            XxHash3::hash_of(&map),
+            0xc467_5a7a_9f1f_d6b2_6c65_39e5_84c1_6e81
+        );
+        #[cfg(feature = "blake3")]
+        assert_eq!(
+            Blake3::hash_of(&map),
+            0xe808_42d8_a337_a0aa_677e_920d_94aa_58ab
+        );
+        #[cfg(feature = "siphash")]
+        assert_eq!(
+            SipHash::hash_of(&map),
+            0x0a1b_8f4b_f616_12cc_efff_e4f9_5c70_b2f8
+        );
+        let _ = map;
     }
 }
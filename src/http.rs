@@ -0,0 +1,258 @@
+//! HTTP endpoints mapping the state hash to an `ETag`, so that syncing behaves like a
+//! conditional request and can sit behind ordinary caches and load balancers.
+//!
+//! * `GET /{id}` with the clients current hash in `If-None-Match` answers `304 Not Modified`
+//!   if the client is up to date, otherwise the [`ClientUpdate`] as JSON. A request without
+//!   `If-None-Match` is treated as coming from a client without any state, and `GET
+//!   /{id}?complete=true` is always answered with a complete update, for a client that needs to
+//!   resync.
+//! * `POST /{id}` with a [`ClientPush`] as JSON answers with the [`ClientUpdate`] as JSON. A
+//!   rejected push is answered with the [`PushError`] as JSON, with `409 Conflict` if the
+//!   server doesn't know the base of the push, otherwise `422 Unprocessable Entity`.
+//!
+//! Every answer carries the hash the client ends up with as its `ETag`. Entity tags have no
+//! room for the [`HashKind`], so clients are assumed to hash like the server, a client that
+//! doesn't still notices from the kind in the [`ClientUpdate`].
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{
+        header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH, VARY},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::de::DeserializeOwned;
+use tokio::net::TcpListener;
+
+use crate::{tcp::SharedServer, trace::TracedId};
+
+use super::*;
+
+/// Build a router serving the server, can be nested into a larger application
+pub fn router<STATE, ID, H>(server: SharedServer<STATE, ID, H>) -> Router
+where
+    STATE: Hash + Clone + Diff + Send + Sync + 'static,
+    STATE::Repr: Serialize + DeserializeOwned + Send + Sync,
+    ID: Hash + Ord + TracedId + DeserializeOwned + Send + Sync + 'static,
+    H: HashAlgorithm + 'static,
+{
+    Router::new()
+        .route(
+            "/{id}",
+            get(get_update::<STATE, ID, H>).post(post_push::<STATE, ID, H>),
+        )
+        .with_state(server)
+}
+
+/// Serve the router on the listener
+pub async fn serve<STATE, ID, H>(
+    listener: TcpListener,
+    server: SharedServer<STATE, ID, H>,
+) -> std::io::Result<()>
+where
+    STATE: Hash + Clone + Diff + Send + Sync + 'static,
+    STATE::Repr: Serialize + DeserializeOwned + Send + Sync,
+    ID: Hash + Ord + TracedId + DeserializeOwned + Send + Sync + 'static,
+    H: HashAlgorithm + 'static,
+{
+    axum::serve(listener, router(server)).await
+}
+
+/// Query parameters of `GET /{id}`
+#[derive(Deserialize)]
+struct UpdateQuery {
+    /// Send a complete update, see [`ClientUpdateRequest`]
+    #[serde(default)]
+    complete: bool,
+}
+
+async fn get_update<STATE, ID, H>(
+    State(server): State<SharedServer<STATE, ID, H>>,
+    Path(id): Path<ID>,
+    Query(query): Query<UpdateQuery>,
+    headers: HeaderMap,
+) -> Response
+where
+    STATE: Hash + Clone + Diff,
+    STATE::Repr: Serialize,
+    ID: Hash + Ord + TracedId,
+    H: HashAlgorithm,
+{
+    let current_hash = match headers.get(IF_NONE_MATCH) {
+        Some(value) => match parse_etag(value) {
+            Some(hash) => hash,
+            None => return (StatusCode::BAD_REQUEST, "malformed If-None-Match").into_response(),
+        },
+        None => H::hash_of(&STATE::identity()),
+    };
+    let update = server.read().await.get_client_diff(ClientUpdateRequest {
+        id,
+        current_hash,
+        hash_kind: H::KIND,
+        trace_id: None,
+        force_complete: query.complete,
+    });
+    respond(update, true)
+}
+
+async fn post_push<STATE, ID, H>(
+    State(server): State<SharedServer<STATE, ID, H>>,
+    Path(id): Path<ID>,
+    Json(push): Json<ClientPush<ID, STATE::Repr>>,
+) -> Response
+where
+    STATE: Hash + Clone + Diff,
+    STATE::Repr: Serialize,
+    ID: Hash + Ord + TracedId,
+    H: HashAlgorithm,
+{
+    if push.id != id {
+        return (StatusCode::BAD_REQUEST, "client id does not match the path").into_response();
+    }
+    match server.write().await.apply_push(push) {
+        Ok(update) => respond(update, false),
+        Err(e @ PushError::UnknownBase { .. }) => (StatusCode::CONFLICT, Json(e)).into_response(),
+        Err(e @ PushError::InvalidPush { .. }) => {
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(e)).into_response()
+        }
+    }
+}
+
+/// Answer with the update, or `304 Not Modified` for a conditional request where the client
+/// is up to date
+fn respond<T: Serialize>(update: ClientUpdate<T>, conditional: bool) -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert(ETAG, format_etag(update.newhash()));
+    // the answer depends on what the client has, so caches have to check with us every time
+    headers.insert(VARY, HeaderValue::from_static("If-None-Match"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+
+    match update {
+        ClientUpdate::Diff {
+            oldhash, newhash, ..
+        } if conditional && oldhash == newhash => {
+            (StatusCode::NOT_MODIFIED, headers).into_response()
+        }
+        update => (headers, Json(update)).into_response(),
+    }
+}
+
+/// Format a hash as a strong entity tag
+pub fn format_etag(hash: StateHash) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{hash:032x}\"")).expect("hex is a valid header value")
+}
+
+/// Parse the first entity tag of an `If-None-Match` or `ETag` header, weak tags are accepted
+/// as the hash means the same thing either way
+pub fn parse_etag(value: &HeaderValue) -> Option<StateHash> {
+    let first = value.to_str().ok()?.split(',').next()?.trim();
+    let tag = first.strip_prefix("W/").unwrap_or(first);
+    let hex = tag.strip_prefix('"')?.strip_suffix('"')?;
+    StateHash::from_str_radix(hex, 16).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    use crate::client::Client;
+
+    use super::*;
+
+    #[derive(Deserialize, Serialize, Diff, Debug, Clone, Hash, PartialEq, Default)]
+    #[diff(attr(#[derive(Serialize, Deserialize)]))]
+    struct Data {
+        values: BTreeMap<u32, String>,
+    }
+
+    async fn get(router: &Router, client: &Client<Data, u32>) -> Response {
+        let request = Request::get(format!("/{}", client.id()))
+            .header(
+                IF_NONE_MATCH,
+                format_etag(client.update_request().current_hash),
+            )
+            .body(Body::empty())
+            .unwrap();
+        router.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn apply(client: &mut Client<Data, u32>, response: Response) {
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = parse_etag(&response.headers()[ETAG]).unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let update: ClientUpdate<<Data as Diff>::Repr> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(update.newhash(), etag);
+        client.apply_update(update).unwrap();
+    }
+
+    #[tokio::test]
+    async fn etag_round_trip() {
+        let server: SharedServer<Data, u32> = Default::default();
+        server.write().await.state.values.insert(1, "one".into());
+        let router = router(server.clone());
+        let mut client: Client<Data, u32> = Client::with_id(7).with_push();
+
+        // without If-None-Match the whole state is sent
+        let request = Request::get("/7").body(Body::empty()).unwrap();
+        apply(&mut client, router.clone().oneshot(request).await.unwrap()).await;
+        assert_eq!(client.state, server.read().await.state);
+
+        let response = get(&router, &client).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(
+            parse_etag(&response.headers()[ETAG]),
+            Some(client.update_request().current_hash)
+        );
+
+        // caches revalidating with no-cache still get the conditional answer
+        let request = Request::get("/7")
+            .header(
+                IF_NONE_MATCH,
+                format_etag(client.update_request().current_hash),
+            )
+            .header(CACHE_CONTROL, "no-cache")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let request = Request::get("/7?complete=true")
+            .header(
+                IF_NONE_MATCH,
+                format_etag(client.update_request().current_hash),
+            )
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let update: ClientUpdate<<Data as Diff>::Repr> = serde_json::from_slice(&body).unwrap();
+        assert!(matches!(update, ClientUpdate::Complete { .. }));
+
+        server.write().await.state.values.insert(2, "two".into());
+        let response = get(&router, &client).await;
+        apply(&mut client, response).await;
+        assert_eq!(client.state, server.read().await.state);
+
+        client.state.values.insert(3, "three".into());
+        let push = client.push_request().unwrap();
+        let request = Request::post("/7")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&push).unwrap()))
+            .unwrap();
+        apply(&mut client, router.clone().oneshot(request).await.unwrap()).await;
+        assert!(server.read().await.state.values.contains_key(&3));
+    }
+
+    #[test]
+    fn etag_parsing() {
+        let hash = 0x0123_4567_89ab_cdef_0123_4567_89ab_cdef;
+        assert_eq!(parse_etag(&format_etag(hash)), Some(hash));
+        let weak = HeaderValue::from_static("W/\"0123456789abcdef0123456789abcdef\", \"00\"");
+        assert_eq!(parse_etag(&weak), Some(hash));
+        assert_eq!(parse_etag(&HeaderValue::from_static("*")), None);
+    }
+}
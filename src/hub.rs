@@ -0,0 +1,253 @@
+//! Many named documents behind one endpoint. Every document is a [`Server`] of its own, and a
+//! client subscribes to a set of them, updating all of them with a single request.
+
+use std::{collections::BTreeSet, fmt::Debug};
+
+use dashmap::{
+    mapref::one::{Ref, RefMut},
+    DashMap,
+};
+
+use crate::{client::Client, customhash::XxHash64, server::Server, trace::TracedId};
+
+use super::*;
+
+/// Update request for every document a client is subscribed to, with the hash of its copy of
+/// each
+#[cfg_attr(feature = "impl_schemars", derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HubRequest<DOC: Ord, ID> {
+    id: ID,
+    #[serde(with = "customhash::hex::map")]
+    #[cfg_attr(feature = "impl_schemars", schemars(with = "BTreeMap<DOC, String>"))]
+    documents: BTreeMap<DOC, StateHash>,
+    #[serde(default)]
+    hash_kind: HashKind,
+    #[serde(default)]
+    trace_id: Option<trace::TraceId>,
+    /// Documents to send complete updates for, see [`ClientUpdateRequest`]
+    #[serde(default)]
+    force_complete: BTreeSet<DOC>,
+}
+
+/// Answer to a [`HubRequest`], with updates for the documents that changed, and the documents
+/// that have been removed. Documents that don't exist yet are in neither
+#[cfg_attr(feature = "impl_schemars", derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HubUpdate<DOC: Ord, T> {
+    pub updates: BTreeMap<DOC, ClientUpdate<T>>,
+    pub removed: BTreeSet<DOC>,
+}
+
+/// Holds the documents, which can be created and removed at any time
+pub struct Hub<DOC, STATE, ID, H = XxHash64>
+where
+    DOC: Hash + Ord,
+    STATE: Diff,
+    ID: Hash + Ord,
+{
+    documents: DashMap<DOC, Server<STATE, ID, H>>,
+    // Documents that have been removed, with the clients known to them that haven't been told
+    // yet, so that they can tell them apart from documents that don't exist yet
+    removed: DashMap<DOC, BTreeSet<ID>>,
+}
+
+impl<DOC: Hash + Ord, STATE: Diff, ID: Hash + Ord, H> Default for Hub<DOC, STATE, ID, H> {
+    fn default() -> Self {
+        Self {
+            documents: DashMap::new(),
+            removed: DashMap::new(),
+        }
+    }
+}
+
+impl<DOC: Hash + Ord, STATE: Diff, ID: Hash + Ord, H> Hub<DOC, STATE, ID, H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a document, replacing and returning any document with the same key. Clients that
+    /// subscribed to it before it existed get it with their next request
+    pub fn insert(&self, doc: DOC, server: Server<STATE, ID, H>) -> Option<Server<STATE, ID, H>> {
+        self.removed.remove(&doc);
+        self.documents.insert(doc, server)
+    }
+
+    /// Remove a document, subscribed clients are told with their next request. The key is
+    /// remembered until every client the document knew has been told, the document is inserted
+    /// again, or [`Hub::forget_removed`] is called
+    pub fn remove(&self, doc: &DOC) -> Option<Server<STATE, ID, H>>
+    where
+        DOC: Clone,
+        ID: Clone,
+    {
+        let (doc, server) = self.documents.remove(doc)?;
+        let waiting = server.client_ids();
+        if !waiting.is_empty() {
+            self.removed.insert(doc, waiting);
+        }
+        Some(server)
+    }
+
+    /// Forget which documents have been removed, clients still subscribed to them keep waiting
+    /// for them to be inserted again
+    pub fn forget_removed(&self) {
+        self.removed.clear();
+    }
+
+    pub fn get(&self, doc: &DOC) -> Option<Ref<'_, DOC, Server<STATE, ID, H>>> {
+        self.documents.get(doc)
+    }
+
+    pub fn get_mut(&self, doc: &DOC) -> Option<RefMut<'_, DOC, Server<STATE, ID, H>>> {
+        self.documents.get_mut(doc)
+    }
+
+    pub fn contains(&self, doc: &DOC) -> bool {
+        self.documents.contains_key(doc)
+    }
+
+    /// Forget a client in every document
+    pub fn forget_client(&self, id: &ID)
+    where
+        ID: Clone,
+    {
+        self.documents
+            .iter_mut()
+            .for_each(|mut server| server.forget_client(id.clone()));
+    }
+}
+
+impl<DOC, STATE, ID, H> Hub<DOC, STATE, ID, H>
+where
+    DOC: Hash + Ord + Clone,
+    STATE: Hash + Clone + Diff,
+    ID: Hash + Ord + Clone + TracedId,
+    H: HashAlgorithm,
+{
+    /// Answer a request for many documents at once, see [`Server::get_client_diff`]. Documents
+    /// that haven't changed since the client's copy are left out of the answer, unless the
+    /// client hashes differently, so that it learns about that
+    pub fn get_client_diff(&self, request: HubRequest<DOC, ID>) -> HubUpdate<DOC, STATE::Repr> {
+        let mut update = HubUpdate {
+            updates: BTreeMap::new(),
+            removed: BTreeSet::new(),
+        };
+        for (doc, current_hash) in request.documents {
+            let force_complete = request.force_complete.contains(&doc);
+            let Some(server) = self.documents.get(&doc) else {
+                if let Some(mut waiting) = self.removed.get_mut(&doc) {
+                    waiting.remove(&request.id);
+                    drop(waiting);
+                    self.removed
+                        .remove_if(&doc, |_, waiting| waiting.is_empty());
+                    update.removed.insert(doc);
+                }
+                continue;
+            };
+            let client_update = server.get_client_diff(ClientUpdateRequest {
+                id: request.id.clone(),
+                current_hash,
+                hash_kind: request.hash_kind,
+                trace_id: request.trace_id,
+                force_complete,
+            });
+            let unchanged = request.hash_kind == H::KIND
+                && matches!(
+                    client_update,
+                    ClientUpdate::Diff { newhash, oldhash, .. } if newhash == oldhash
+                );
+            if !unchanged {
+                update.updates.insert(doc, client_update);
+            }
+        }
+        update
+    }
+}
+
+/// Client side of a [`Hub`], with a [`Client`] per subscribed document
+pub struct HubClient<DOC: Ord, STATE: Default + Diff, ID, H = XxHash64> {
+    id: ID,
+    documents: BTreeMap<DOC, Client<STATE, ID, H>>,
+}
+
+impl<DOC, STATE, ID, H> HubClient<DOC, STATE, ID, H>
+where
+    DOC: Ord + Clone,
+    STATE: Hash + Diff + Default,
+    ID: Clone + TracedId,
+    H: HashAlgorithm,
+{
+    pub fn with_id(id: ID) -> Self {
+        Self {
+            id,
+            documents: BTreeMap::new(),
+        }
+    }
+
+    /// Start following a document, it arrives with the first update after it exists in the hub
+    pub fn subscribe(&mut self, doc: DOC) {
+        let id = self.id.clone();
+        self.documents
+            .entry(doc)
+            .or_insert_with(|| Client::with_id(id));
+    }
+
+    pub fn unsubscribe(&mut self, doc: &DOC) -> Option<Client<STATE, ID, H>> {
+        self.documents.remove(doc)
+    }
+
+    pub fn get(&self, doc: &DOC) -> Option<&Client<STATE, ID, H>> {
+        self.documents.get(doc)
+    }
+
+    pub fn get_mut(&mut self, doc: &DOC) -> Option<&mut Client<STATE, ID, H>> {
+        self.documents.get_mut(doc)
+    }
+
+    pub fn update_request(&self) -> HubRequest<DOC, ID> {
+        HubRequest {
+            id: self.id.clone(),
+            documents: self
+                .documents
+                .iter()
+                .map(|(doc, client)| (doc.clone(), client.update_request().current_hash))
+                .collect(),
+            hash_kind: H::KIND,
+            trace_id: trace::new_trace_id(),
+            force_complete: self
+                .documents
+                .iter()
+                .filter(|(_, client)| client.needs_resync())
+                .map(|(doc, _)| doc.clone())
+                .collect(),
+        }
+    }
+
+    /// Apply the updates for every document, dropping the removed ones. A document that fails
+    /// to update is left as it was, and gets another chance with the next request
+    pub fn apply_update(
+        &mut self,
+        update: HubUpdate<DOC, STATE::Repr>,
+    ) -> Result<(), Vec<(DOC, UpdateError)>> {
+        for doc in &update.removed {
+            self.documents.remove(doc);
+        }
+        let errors: Vec<_> = update
+            .updates
+            .into_iter()
+            .filter_map(|(doc, client_update)| {
+                let client = self.documents.get_mut(&doc)?;
+                client
+                    .apply_update(client_update)
+                    .err()
+                    .map(|err| (doc, err))
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
@@ -1,53 +1,223 @@
-#![feature(hasher_prefixfree_extras)]
-
 use dashmap::DashMap;
 pub use diff::*;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeMap,
+    fmt,
     hash::{Hash, Hasher},
 };
 
+pub use customhash::{HashAlgorithm, HashKind, StateHash};
 pub use structs::SimpleDiff;
 
 // implementation
 pub mod client;
+pub mod codec;
+pub mod compression;
+pub mod conflict;
 pub mod customhash;
+pub mod hub;
+pub mod merkle;
+pub mod projection;
 pub mod server;
+pub mod stats;
+pub mod store;
 pub mod structs;
+pub mod trace;
 
 pub use concmap::ConcMap;
 
 /// Concurrent wrapper for dashmap, to implement all ze traits on
 pub mod concmap;
 
+/// Framed TCP transport on tokio
+#[cfg(feature = "tokio")]
+pub mod tcp;
+
+/// WebSocket transport on tokio
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+/// HTTP endpoints with the state hash as ETag
+#[cfg(feature = "http")]
+pub mod http;
+
+/// The answer of the server to an update request. Like every protocol message, its hashes are
+/// written as hex strings in human readable formats such as JSON, see [`customhash::hex`]
 #[cfg_attr(feature = "impl_schemars", derive(schemars::JsonSchema))]
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub enum ClientUpdate<T> {
     Complete {
         // Technicalities makes this more feasible than return the entire STATE, especially for those cases when STATE is in an Arc
         complete_diff: T,
-        newhash: u64,
+        #[serde(with = "customhash::hex")]
+        #[cfg_attr(feature = "impl_schemars", schemars(with = "String"))]
+        newhash: StateHash,
+        #[serde(default)]
+        hash_kind: HashKind,
     },
     Diff {
         /// only a diff needs to be applied, and equal hash means that the diff applied succesfully
         diff: T,
-        newhash: u64,
-        oldhash: u64,
+        #[serde(with = "customhash::hex")]
+        #[cfg_attr(feature = "impl_schemars", schemars(with = "String"))]
+        newhash: StateHash,
+        #[serde(with = "customhash::hex")]
+        #[cfg_attr(feature = "impl_schemars", schemars(with = "String"))]
+        oldhash: StateHash,
+        #[serde(default)]
+        hash_kind: HashKind,
     },
 }
 
-#[derive(Debug)]
+impl<T> ClientUpdate<T> {
+    /// Hash of the state the client will be in after applying the update
+    pub fn newhash(&self) -> StateHash {
+        match self {
+            ClientUpdate::Complete { newhash, .. } | ClientUpdate::Diff { newhash, .. } => *newhash,
+        }
+    }
+
+    /// The algorithm the server hashed with
+    pub fn hash_kind(&self) -> HashKind {
+        match self {
+            ClientUpdate::Complete { hash_kind, .. } | ClientUpdate::Diff { hash_kind, .. } => {
+                *hash_kind
+            }
+        }
+    }
+}
+
+/// Why an update could not be applied. The client is left as it was, and asks for a complete
+/// update with its next request, see [`client::Client::needs_resync`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UpdateError {
-    InvalidUpdateStartState,
-    HashResultDiff,
+    /// The update is for another state than the one the client is in
+    InvalidUpdateStartState {
+        expected: StateHash,
+        actual: StateHash,
+    },
+    /// Applying the update didn't give the state the server has
+    HashResultDiff {
+        expected: StateHash,
+        actual: StateHash,
+    },
+    /// The server hashes with a different algorithm than the client
+    HashKindMismatch { client: HashKind, server: HashKind },
 }
 
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUpdateStartState { expected, actual } => write!(
+                f,
+                "update starts from state {expected:x}, but the client is in {actual:x}"
+            ),
+            Self::HashResultDiff { expected, actual } => write!(
+                f,
+                "update should give state {expected:x}, but gave {actual:x}"
+            ),
+            Self::HashKindMismatch { client, server } => write!(
+                f,
+                "client hashes with {client:?}, but the server with {server:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
+/// Why a push was not merged. The client keeps its local changes, and acts on the error with
+/// [`client::Client::push_rejected`]
+#[cfg_attr(feature = "impl_schemars", derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushError {
+    /// The server no longer has the version the changes are based on, so the client pushes
+    /// again with the whole version attached
+    UnknownBase {
+        #[serde(with = "customhash::hex")]
+        #[cfg_attr(feature = "impl_schemars", schemars(with = "String"))]
+        base_hash: StateHash,
+    },
+    /// The diff could not be applied to the version it is based on, or the attached version
+    /// doesn't have the hash it is pushed with
+    InvalidPush {
+        #[serde(with = "customhash::hex")]
+        #[cfg_attr(feature = "impl_schemars", schemars(with = "String"))]
+        base_hash: StateHash,
+    },
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownBase { base_hash } => {
+                write!(f, "push is based on state {base_hash:x}, which is unknown")
+            }
+            Self::InvalidPush { base_hash } => {
+                write!(f, "push can't be applied to state {base_hash:x}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PushError {}
+
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ClientUpdateRequest<ID> {
     id: ID,
-    current_hash: u64,
+    #[serde(with = "customhash::hex")]
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    current_hash: StateHash,
+    #[serde(default)]
+    hash_kind: HashKind,
+    #[serde(default)]
+    trace_id: Option<trace::TraceId>,
+    /// Skip any baseline and send a complete update, for clients that need to resync
+    #[serde(default)]
+    force_complete: bool,
+}
+
+/// Confirmation from a client that it has applied an update, and now has the state with the given hash
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientAck<ID> {
+    id: ID,
+    #[serde(with = "customhash::hex")]
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    hash: StateHash,
+}
+
+/// Changes made locally by a client, sent to the server to be merged into its state. The server
+/// answers with a regular [`ClientUpdate`] bringing the client to the merged state, or a
+/// [`PushError`]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientPush<ID, T> {
+    id: ID,
+    /// hash of the server confirmed state the changes are based on
+    #[serde(with = "customhash::hex")]
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    base_hash: StateHash,
+    diff: T,
+    /// The whole base as a diff from the identity, attached after the server answered with
+    /// [`PushError::UnknownBase`]
+    base: Option<T>,
+    /// milliseconds since the unix epoch
+    timestamp: u64,
+    #[serde(default)]
+    trace_id: Option<trace::TraceId>,
+}
+
+/// Everything a client sends to the server, for transports carrying a single message type.
+/// `Update` and `Push` are answered with a [`ClientUpdate`], `Ack` is not answered
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ClientMessage<ID, T> {
+    Update(ClientUpdateRequest<ID>),
+    Push(ClientPush<ID, T>),
+    Ack(ClientAck<ID>),
 }
 
 #[cfg(test)]
@@ -58,6 +228,7 @@ mod test {
     use rand::rngs::ThreadRng;
     use random_variant::RandomVariant;
 
+    #[allow(dead_code)]
     pub trait Empty {
         fn empty(&self) -> bool;
     }
@@ -105,6 +276,15 @@ mod test {
         pub tags: BTreeMap<u32, Tag>,
     }
 
+    impl conflict::Merge for Data {
+        fn merge(base: &Self, winner: &Self, other: &Self) -> Self {
+            Data {
+                anchors: conflict::Merge::merge(&base.anchors, &winner.anchors, &other.anchors),
+                tags: conflict::Merge::merge(&base.tags, &winner.tags, &other.tags),
+            }
+        }
+    }
+
     #[test]
     fn update_works() {
         let mut rng = ThreadRng::default();
@@ -152,11 +332,13 @@ mod test {
             ClientUpdate::Complete {
                 complete_diff: _,
                 newhash: _,
-            } => assert!(false, "Should not be a complete update!"),
+                hash_kind: _,
+            } => panic!("Should not be a complete update!"),
             ClientUpdate::Diff {
                 diff: _,
                 newhash: _,
                 oldhash: _,
+                hash_kind: _,
             } => {
                 //println!("newhash: {newhash}");
             }
@@ -166,4 +348,1131 @@ mod test {
         println!("{res:?}");
         assert_eq!(client.state, server.state);
     }
+
+    #[test]
+    fn lost_update_gives_diff() {
+        let mut rng = ThreadRng::default();
+
+        let mut client: client::Client<Data, u32> = client::Client::with_id(1337);
+        let mut server: server::Server<Data, u32> = server::Server::default();
+        for i in 0..100 {
+            server
+                .state
+                .anchors
+                .insert(i, Anchor::random_variant(&mut rng));
+        }
+
+        let client_update = server.get_client_diff(client.update_request());
+        assert!(client.apply_update(client_update).is_ok());
+        server.acknowledge(client.acknowledgement());
+
+        // this update never reaches the client
+        server
+            .state
+            .anchors
+            .insert(0, Anchor::random_variant(&mut rng));
+        let _lost = server.get_client_diff(client.update_request());
+
+        server
+            .state
+            .anchors
+            .insert(1, Anchor::random_variant(&mut rng));
+        let client_update = server.get_client_diff(client.update_request());
+        assert!(matches!(client_update, ClientUpdate::Diff { .. }));
+        assert!(client.apply_update(client_update).is_ok());
+        assert_eq!(client.state, server.state);
+
+        // without an explicit ack, the next request confirms the previous update
+        server
+            .state
+            .anchors
+            .insert(2, Anchor::random_variant(&mut rng));
+        let client_update = server.get_client_diff(client.update_request());
+        assert!(matches!(client_update, ClientUpdate::Diff { .. }));
+        assert!(client.apply_update(client_update).is_ok());
+        assert_eq!(client.state, server.state);
+    }
+
+    #[test]
+    fn aged_out_version_gives_complete() {
+        let mut rng = ThreadRng::default();
+
+        let mut client: client::Client<Data, u32> = client::Client::with_id(1);
+        let other: client::Client<Data, u32> = client::Client::with_id(2);
+        let mut server: server::Server<Data, u32> = server::Server::default().with_history_len(1);
+
+        let client_update = server.get_client_diff(client.update_request());
+        assert!(client.apply_update(client_update).is_ok());
+
+        // another client requesting commits a new version, pushing out the one the client is on
+        server.state.tags.insert(0, Tag::random_variant(&mut rng));
+        let _ = server.get_client_diff(other.update_request());
+
+        server.state.tags.insert(1, Tag::random_variant(&mut rng));
+        let client_update = server.get_client_diff(client.update_request());
+        assert!(matches!(client_update, ClientUpdate::Complete { .. }));
+        assert!(client.apply_update(client_update).is_ok());
+        assert_eq!(client.state, server.state);
+
+        // a client unknown to the server, but in a known version, gets a diff
+        let mut restarted: client::Client<Data, u32> = client::Client::with_id(3);
+        restarted.state = client.state.clone();
+        let client_update = server.get_client_diff(restarted.update_request());
+        assert!(matches!(client_update, ClientUpdate::Diff { .. }));
+    }
+
+    #[test]
+    fn failed_apply_keeps_state() {
+        let mut rng = ThreadRng::default();
+
+        // the baseline of a client that pushes doubles as the copy to roll back to
+        let mut client: client::Client<Data, u32> = client::Client::with_id(1).with_push();
+        let mut server: server::Server<Data, u32> = server::Server::default();
+        for i in 0..10 {
+            server.state.tags.insert(i, Tag::random_variant(&mut rng));
+        }
+        let client_update = server.get_client_diff(client.update_request());
+        assert!(client.apply_update(client_update).is_ok());
+        let before = client.state.clone();
+
+        server.state.tags.insert(0, Tag::random_variant(&mut rng));
+        let client_update = server.get_client_diff(client.update_request());
+        let ClientUpdate::Diff {
+            diff,
+            oldhash,
+            hash_kind,
+            ..
+        } = client_update
+        else {
+            panic!("Should be a diff update!");
+        };
+        let corrupted = ClientUpdate::Diff {
+            diff,
+            newhash: 0,
+            oldhash,
+            hash_kind,
+        };
+        assert!(matches!(
+            client.apply_update(corrupted),
+            Err(UpdateError::HashResultDiff { expected: 0, .. })
+        ));
+        assert_eq!(client.state, before);
+
+        let complete_diff = Data::identity().diff(&server.state);
+        let corrupted = ClientUpdate::Complete {
+            complete_diff,
+            newhash: 0,
+            hash_kind,
+        };
+        assert!(matches!(
+            client.apply_update(corrupted),
+            Err(UpdateError::HashResultDiff { expected: 0, .. })
+        ));
+        assert_eq!(client.state, before);
+
+        // the client is in a version the server knows, but asks for a complete update anyway
+        assert!(client.needs_resync());
+        let request = client.update_request();
+        assert!(request.force_complete);
+        let update = server.get_client_diff(request);
+        assert!(matches!(update, ClientUpdate::Complete { .. }));
+        assert!(client.apply_update(update).is_ok());
+        assert!(!client.needs_resync());
+        assert_eq!(client.state, server.state);
+        assert_eq!(server.stats().sync.completes_requested, 1);
+
+        let stale = ClientUpdate::Diff {
+            diff: Data::identity().diff(&Data::identity()),
+            newhash: 1,
+            oldhash: 2,
+            hash_kind,
+        };
+        let error = client.apply_update(stale).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "update starts from state 2, but the client is in {:x}",
+                client.update_request().current_hash
+            )
+        );
+
+        // a client without a copy applies diffs in place, and is repaired by a complete update
+        let mut follower: client::Client<Data, u32> = client::Client::with_id(2);
+        let update = server.get_client_diff(follower.update_request());
+        assert!(follower.apply_update(update).is_ok());
+        server.state.tags.insert(0, Tag::random_variant(&mut rng));
+        let ClientUpdate::Diff { diff, oldhash, .. } =
+            server.get_client_diff(follower.update_request())
+        else {
+            panic!("Should be a diff update!");
+        };
+        let corrupted = ClientUpdate::Diff {
+            diff,
+            newhash: 0,
+            oldhash,
+            hash_kind,
+        };
+        assert!(follower.apply_update(corrupted).is_err());
+        assert!(follower.needs_resync());
+        let update = server.get_client_diff(follower.update_request());
+        assert!(matches!(update, ClientUpdate::Complete { .. }));
+        assert!(follower.apply_update(update).is_ok());
+        assert_eq!(follower.state, server.state);
+    }
+
+    #[test]
+    fn failed_diff_is_undone() {
+        use structs::InvertDiff;
+
+        let map: ConcMap<u32, u32> = (0..10).map(|i| (i, i)).collect();
+        let server: server::Server<_, u32> = server::Server::new(map);
+        let mut client: client::Client<ConcMap<u32, u32>, u32> = client::Client::with_id(1)
+            .with_undo(|state, diff: &SimpleDiff<u32, u32>| diff.invert(state));
+        let update = server.get_client_diff(client.update_request());
+        assert!(client.apply_update(update).is_ok());
+
+        // with a local change the baseline is no copy to roll back to
+        client.state.insert(20, 20);
+        let before = client.state.as_btree();
+        let mut diff = SimpleDiff::new();
+        diff.altered.extend([(1, 100), (21, 21)]);
+        diff.removed.extend([2, 20]);
+        let corrupted = ClientUpdate::Diff {
+            diff,
+            newhash: 0,
+            oldhash: client.update_request().current_hash,
+            hash_kind: HashKind::XxHash64,
+        };
+        assert!(matches!(
+            client.apply_update(corrupted),
+            Err(UpdateError::HashResultDiff { expected: 0, .. })
+        ));
+        assert_eq!(client.state.as_btree(), before);
+    }
+
+    #[test]
+    fn mismatched_hash_kind_is_detected() {
+        let mut client: client::Client<Data, u32> = client::Client::with_id(1);
+        let mut server: server::Server<Data, u32> = server::Server::default();
+        server.state.tags.insert(0, Tag::default());
+        let ClientUpdate::Complete {
+            complete_diff,
+            newhash,
+            ..
+        } = server.get_client_diff(client.update_request())
+        else {
+            panic!("Should be a complete update!");
+        };
+        let update = ClientUpdate::Complete {
+            complete_diff,
+            newhash,
+            hash_kind: HashKind::Blake3,
+        };
+        assert!(matches!(
+            client.apply_update(update),
+            Err(UpdateError::HashKindMismatch {
+                client: HashKind::XxHash64,
+                server: HashKind::Blake3,
+            })
+        ));
+        assert!(!client.needs_resync());
+
+        // the server doesn't send its state to a client that can't use it
+        let mut request = client.update_request();
+        request.hash_kind = HashKind::Blake3;
+        request.id = 2;
+        let update = server.get_client_diff(request);
+        assert_eq!(update.hash_kind(), HashKind::XxHash64);
+        assert!(
+            matches!(update, ClientUpdate::Diff { oldhash, newhash, .. } if oldhash == newhash)
+        );
+        assert_eq!(server.client_count(), 1);
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn wide_hashes_sync() {
+        use customhash::Blake3;
+
+        let mut rng = ThreadRng::default();
+        let mut client: client::Client<Data, u32, Blake3> = client::Client::with_id(1);
+        let mut server: server::Server<Data, u32, Blake3> = server::Server::default();
+        for i in 0..10 {
+            server.state.tags.insert(i, Tag::random_variant(&mut rng));
+        }
+        let request = client.update_request();
+        let update = server.get_client_diff(request);
+        assert_eq!(update.hash_kind(), HashKind::Blake3);
+        client.apply_update(update).unwrap();
+        server.state.tags.insert(0, Tag::random_variant(&mut rng));
+        let update = server.get_client_diff(client.update_request());
+        assert!(matches!(update, ClientUpdate::Diff { .. }));
+        client.apply_update(update).unwrap();
+        assert_eq!(client.state, server.state);
+    }
+
+    #[test]
+    fn reconcile_unknown_client() {
+        let mut rng = ThreadRng::default();
+
+        let mut server: server::Server<Data, u32> = server::Server::default();
+        for i in 0..2000 {
+            server.state.tags.insert(i, Tag::random_variant(&mut rng));
+        }
+        for i in 0..10 {
+            server
+                .state
+                .anchors
+                .insert(i, Anchor::random_variant(&mut rng));
+        }
+        // a client the server doesn't know, with almost all of the state
+        let mut client: client::Client<Data, u32> = client::Client::with_id(1);
+        client.state = server.state.clone();
+        client.state.tags.remove(&17);
+        client
+            .state
+            .tags
+            .insert(3000, Tag::random_variant(&mut rng));
+        client
+            .state
+            .tags
+            .insert(1500, Tag::random_variant(&mut rng));
+        client.state.anchors.clear();
+
+        let mut rounds = 0;
+        let mut transferred = 0;
+        let mut request = Some(client.reconcile_request(|data| &data.tags));
+        while let Some(next) = request {
+            let response = server.reconcile(|data| &data.tags, next);
+            transferred += response
+                .entries
+                .iter()
+                .map(|range| range.entries.len())
+                .sum::<usize>();
+            request = client.apply_reconcile(|data| &mut data.tags, response);
+            rounds += 1;
+        }
+        assert_eq!(client.state.tags, server.state.tags);
+        assert!(rounds <= 4, "took {rounds} rounds");
+        assert!(transferred < 200, "transferred {transferred} entries");
+
+        let mut request = Some(client.reconcile_request(|data| &data.anchors));
+        while let Some(next) = request {
+            let response = server.reconcile(|data| &data.anchors, next);
+            request = client.apply_reconcile(|data| &mut data.anchors, response);
+        }
+        assert_eq!(client.state, server.state);
+        assert!(!client.has_local_changes());
+
+        // the server knows the version the client ended up in
+        let client_update = server.get_client_diff(client.update_request());
+        assert!(matches!(client_update, ClientUpdate::Diff { .. }));
+    }
+
+    #[test]
+    fn concmap_digest_is_order_independent() {
+        let hash = |map: &ConcMap<u32, String>| customhash::XxHash64::hash_of(map);
+        let a: ConcMap<u32, String> = (0..100).map(|i| (i, i.to_string())).collect();
+        let b: ConcMap<u32, String> = (0..100).rev().map(|i| (i, i.to_string())).collect();
+        assert_eq!(hash(&a), hash(&b));
+
+        b.insert(1000, "extra".into());
+        b.insert(5, "changed".into());
+        assert_ne!(hash(&a), hash(&b));
+        b.remove(&1000);
+        b.insert(5, "5".into());
+        assert_eq!(hash(&a), hash(&b));
+
+        b.retain(|key, _| key % 2 == 0);
+        let evens: ConcMap<u32, String> = (0..50).map(|i| (i * 2, (i * 2).to_string())).collect();
+        assert_eq!(hash(&b), hash(&evens));
+
+        let copy: ConcMap<u32, String> =
+            serde_json::from_str(&serde_json::to_string(&a).unwrap()).unwrap();
+        assert_eq!(hash(&copy), hash(&a));
+        assert_eq!(hash(&a.clone()), hash(&a));
+        b.clear();
+        assert_eq!(hash(&b), hash(&ConcMap::new()));
+    }
+
+    #[cfg(feature = "xxh3")]
+    #[test]
+    fn concmap_digest_uses_the_map_algorithm() {
+        use customhash::XxHash3;
+
+        let map: ConcMap<u32, u32, XxHash3> = (0..10).map(|i| (i, i)).collect();
+        let (low, high) = (0..10u32).map(|i| XxHash3::hash_of(&(i, i))).fold(
+            (0u64, 0u64),
+            |(low, high), hash| {
+                (
+                    low.wrapping_add(hash as u64),
+                    high.wrapping_add((hash >> 64) as u64),
+                )
+            },
+        );
+        assert_ne!(high, 0);
+        let expected = XxHash3::hash_of(&(10usize, [low, high]));
+        assert_eq!(XxHash3::hash_of(&map), expected);
+        assert_eq!(map.inner().len(), 10);
+    }
+
+    #[test]
+    fn concmap_journal_diff_matches_full_diff() {
+        let map: ConcMap<u32, String> = (0..100).map(|i| (i, i.to_string())).collect();
+        let old = map.clone();
+        map.insert(1000, "new".into());
+        map.insert(5, "changed".into());
+        map.insert(6, "6".into());
+        map.remove(&7);
+        map.retain(|key, _| *key != 8);
+
+        let diff = map.changes_since(&old).expect("journal covers the clone");
+        assert_eq!(diff.altered.keys().collect::<Vec<_>>(), [&5, &1000]);
+        assert_eq!(diff.removed.iter().collect::<Vec<_>>(), [&7, &8]);
+        let full: SimpleDiff<u32, String> =
+            structs::SimpleDiffTrait::generate(&old.as_btree(), &map.as_btree());
+        assert_eq!(diff.altered, full.altered);
+        assert_eq!(diff.removed, full.removed);
+        let mut updated = old.clone();
+        updated.apply(&old.diff(&map));
+        assert_eq!(updated.as_btree(), map.as_btree());
+
+        // an unrelated map, a changed clone or one the journal no longer reaches is walked fully
+        let other: ConcMap<u32, String> = (0..100).map(|i| (i, i.to_string())).collect();
+        assert!(map.changes_since(&other).is_none());
+        let changed = map.clone();
+        changed.insert(1, "local".into());
+        assert!(map.changes_since(&changed).is_none());
+        let short = ConcMap::new().with_journal_len(2);
+        let before = short.clone();
+        (0..3).for_each(|i| {
+            short.insert(i, i.to_string());
+        });
+        assert!(short.changes_since(&before).is_none());
+        assert_eq!(before.diff(&short).altered.len(), 3);
+    }
+
+    #[test]
+    fn simple_diffs_compose_and_invert() {
+        use structs::{InvertDiff, SimpleDiffTrait};
+        let map = |entries: &[(u32, &str)]| -> BTreeMap<u32, String> {
+            entries.iter().map(|(k, v)| (*k, v.to_string())).collect()
+        };
+        let a = map(&[(1, "a"), (2, "b"), (3, "c")]);
+        let b = map(&[(1, "a"), (2, "B"), (4, "d")]);
+        let c = map(&[(1, "a"), (3, "C"), (4, "d"), (5, "e")]);
+        let ab = SimpleDiff::generate(&a, &b);
+        let bc = SimpleDiff::generate(&b, &c);
+
+        let ac = SimpleDiff::compose(&ab, &bc);
+        let mut applied = a.clone();
+        ac.apply_to(&mut applied);
+        assert_eq!(applied, c);
+        // 2 is altered then removed, 3 removed then re-added
+        assert_eq!(ac.removed.iter().collect::<Vec<_>>(), [&2]);
+        assert_eq!(ac.altered.get(&3).map(String::as_str), Some("C"));
+
+        let mut undone = b.clone();
+        ab.invert(&a).apply_to(&mut undone);
+        assert_eq!(undone, a);
+        let mut undone = c.clone();
+        ac.invert(&a).apply_to(&mut undone);
+        assert_eq!(undone, a);
+
+        let mut concurrent: ConcMap<u32, String> = a.clone().into_iter().collect();
+        let inverse = ab.invert(&concurrent);
+        assert_eq!(inverse, ab.invert(&a));
+        ab.apply_to(&mut concurrent);
+        inverse.apply_to(&mut concurrent);
+        assert_eq!(concurrent.as_btree(), a);
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    #[test]
+    fn ids_need_no_debug_without_tracing() {
+        #[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+        struct Id(u32);
+        let server: server::Server<Data, Id> = server::Server::default();
+        let mut client: client::Client<Data, Id> = client::Client::with_id(Id(1));
+        let update = server.get_client_diff(client.update_request());
+        assert!(client.apply_update(update).is_ok());
+    }
+
+    fn synced_pair<STATE: Hash + Diff + Clone + Default>(
+        server: server::Server<STATE, u32>,
+    ) -> (client::Client<STATE, u32>, server::Server<STATE, u32>) {
+        let mut client = client::Client::with_id(1).with_push();
+        let client_update = server.get_client_diff(client.update_request());
+        assert!(client.apply_update(client_update).is_ok());
+        (client, server)
+    }
+
+    #[test]
+    fn delta_chain_history() {
+        let map: ConcMap<u32, u32> = (0..10).map(|i| (i, i)).collect();
+        let server = server::Server::new(map)
+            .with_history_len(16)
+            .with_delta_chain(4, SimpleDiff::compose)
+            .with_conflict_policy(conflict::ConflictPolicy::last_writer_wins_per_key());
+        let (mut client, mut server) = synced_pair(server);
+        let mut behind: client::Client<ConcMap<u32, u32>, u32> = client::Client::with_id(2);
+        let update = server.get_client_diff(behind.update_request());
+        assert!(behind.apply_update(update).is_ok());
+
+        let mut between: client::Client<ConcMap<u32, u32>, u32> = client::Client::with_id(3);
+        for round in 0..10 {
+            server.state.insert(round, round * 100);
+            server.state.remove(&(round + 5));
+            server.commit();
+            let client_update = server.get_client_diff(client.update_request());
+            assert!(matches!(client_update, ClientUpdate::Diff { .. }));
+            assert!(client.apply_update(client_update).is_ok());
+            assert_eq!(client.state.as_btree(), server.state.as_btree());
+            if round == 2 {
+                let update = server.get_client_diff(between.update_request());
+                assert!(between.apply_update(update).is_ok());
+            }
+        }
+
+        // ten versions behind, past two keyframes, the version is rebuilt from the keyframe
+        // before it, while three versions past a keyframe the chain is still composed
+        for lagging in [&mut behind, &mut between] {
+            let update = server.get_client_diff(lagging.update_request());
+            assert!(matches!(update, ClientUpdate::Diff { .. }));
+            assert!(lagging.apply_update(update).is_ok());
+            assert_eq!(lagging.state.as_btree(), server.state.as_btree());
+        }
+        assert_eq!(server.stats().sync.completes_too_far_behind, 0);
+
+        // a push based on a version between keyframes has its base rebuilt from the chain
+        client.state.insert(100, 1);
+        server.state.insert(200, 2);
+        server.commit();
+        let client_update = server.apply_push(client.push_request().unwrap()).unwrap();
+        assert!(client.apply_update(client_update).is_ok());
+        assert_eq!(client.state.as_btree(), server.state.as_btree());
+        assert_eq!(server.state.get(&100).as_deref(), Some(&1));
+        assert_eq!(server.state.get(&200).as_deref(), Some(&2));
+    }
+
+    #[test]
+    fn projected_views() {
+        let mut server = tag_server(conflict::ConflictPolicy::ServerWins).with_max_clients(2);
+        // every client only sees its own tag
+        let projection: projection::Projection<Data, u32, BTreeMap<u32, Tag>> =
+            projection::Projection::new(|id: &u32, state: &Data| {
+                state
+                    .tags
+                    .iter()
+                    .filter(|(key, _)| *key == id)
+                    .map(|(key, tag)| (*key, tag.clone()))
+                    .collect()
+            });
+        let mut client: client::Client<BTreeMap<u32, Tag>, u32> = client::Client::with_id(1);
+        let update = projection.get_client_diff(&server, client.update_request());
+        assert!(matches!(update, ClientUpdate::Complete { .. }));
+        assert!(client.apply_update(update).is_ok());
+        assert_eq!(client.state.keys().collect::<Vec<_>>(), [&1]);
+
+        server.state.tags.get_mut(&2).unwrap().battery += 1;
+        let update = projection.get_client_diff(&server, client.update_request());
+        assert_eq!(update.newhash(), client.update_request().current_hash);
+        assert!(client.apply_update(update).is_ok());
+
+        server.state.tags.get_mut(&1).unwrap().battery += 1;
+        let update = projection.get_client_diff(&server, client.update_request());
+        assert!(matches!(update, ClientUpdate::Diff { .. }));
+        assert!(client.apply_update(update).is_ok());
+        assert_eq!(client.state[&1], server.state.tags[&1]);
+        assert_eq!(client.state.len(), 1);
+
+        assert_eq!(server.client_stats(&1).unwrap().diffs, 2);
+
+        // a client whose view was projected from a version that's still around gets a diff
+        server.state.tags.get_mut(&1).unwrap().battery += 1;
+        for _ in 0..4 {
+            server.state.tags.get_mut(&2).unwrap().battery += 1;
+            server.commit();
+        }
+        let update = projection.get_client_diff(&server, client.update_request());
+        assert!(matches!(update, ClientUpdate::Diff { .. }));
+        assert!(client.apply_update(update).is_ok());
+        assert_eq!(client.state[&1], server.state.tags[&1]);
+
+        // a client hashing differently is told so with an empty diff
+        let mut request = client.update_request();
+        request.hash_kind = HashKind::SipHash;
+        let update = projection.get_client_diff(&server, request);
+        assert_eq!(update.newhash(), client.update_request().current_hash);
+
+        // the clients of a projection are limited like the server's own
+        for id in [2, 3] {
+            let mut other: client::Client<BTreeMap<u32, Tag>, u32> = client::Client::with_id(id);
+            let update = projection.get_client_diff(&server, other.update_request());
+            assert!(other.apply_update(update).is_ok());
+        }
+        assert_eq!(server.client_count(), 2);
+        let update = projection.get_client_diff(&server, client.update_request());
+        assert!(matches!(update, ClientUpdate::Complete { .. }));
+    }
+
+    #[test]
+    fn hub_updates_many_documents() {
+        let hub: hub::Hub<String, Data, u32> = hub::Hub::new();
+        hub.insert("site-a".into(), tag_server(Default::default()));
+        hub.insert("site-b".into(), tag_server(Default::default()));
+        let mut client: hub::HubClient<String, Data, u32> = hub::HubClient::with_id(1);
+        client.subscribe("site-a".into());
+        client.subscribe("site-b".into());
+        client.subscribe("site-c".into());
+        let mut other: hub::HubClient<String, Data, u32> = hub::HubClient::with_id(2);
+        other.subscribe("site-a".into());
+        assert!(other
+            .apply_update(hub.get_client_diff(other.update_request()))
+            .is_ok());
+
+        let update = hub.get_client_diff(client.update_request());
+        assert_eq!(update.updates.len(), 2);
+        assert!(update.removed.is_empty());
+        assert!(client.apply_update(update).is_ok());
+        // the subscription waits for the document to be created
+        assert!(client.get(&"site-c".into()).is_some());
+        for doc in ["site-a", "site-b"] {
+            let server = hub.get(&doc.to_string()).unwrap();
+            assert_eq!(client.get(&doc.into()).unwrap().state, server.state);
+        }
+
+        // only changed documents are answered, unless the client hashes differently
+        hub.get_mut(&"site-b".into()).unwrap().state.tags.remove(&1);
+        let mut request = serde_json::to_value(client.update_request()).unwrap();
+        request["hash_kind"] = serde_json::to_value(HashKind::Blake3).unwrap();
+        let update = hub.get_client_diff(serde_json::from_value(request).unwrap());
+        assert_eq!(update.updates.len(), 2);
+        let update = hub.get_client_diff(client.update_request());
+        assert_eq!(update.updates.keys().collect::<Vec<_>>(), ["site-b"]);
+        assert!(client.apply_update(update).is_ok());
+        assert!(!client
+            .get(&"site-b".into())
+            .unwrap()
+            .state
+            .tags
+            .contains_key(&1));
+
+        hub.insert("site-c".into(), tag_server(Default::default()));
+        let update = hub.get_client_diff(client.update_request());
+        assert_eq!(update.updates.keys().collect::<Vec<_>>(), ["site-c"]);
+        assert!(client.apply_update(update).is_ok());
+        assert_eq!(
+            client.get(&"site-c".into()).unwrap().state,
+            hub.get(&"site-c".into()).unwrap().state
+        );
+
+        hub.remove(&"site-a".into());
+        let update = hub.get_client_diff(client.update_request());
+        assert!(update.updates.is_empty());
+        assert!(update.removed.contains("site-a"));
+        assert!(client.apply_update(update).is_ok());
+        assert!(client.get(&"site-a".into()).is_none());
+
+        // the removal is remembered until every client that knew the document has been told
+        let update = hub.get_client_diff(other.update_request());
+        assert!(update.removed.contains("site-a"));
+        let mut late: hub::HubClient<String, Data, u32> = hub::HubClient::with_id(3);
+        late.subscribe("site-a".into());
+        assert!(hub
+            .get_client_diff(late.update_request())
+            .removed
+            .is_empty());
+    }
+
+    #[test]
+    fn idle_and_least_recently_used_clients_are_evicted() {
+        let server = tag_server(Default::default()).with_max_clients(2);
+        let mut clients: Vec<client::Client<Data, u32>> =
+            (0..3).map(client::Client::with_id).collect();
+        for client in &mut clients {
+            let update = server.get_client_diff(client.update_request());
+            assert!(client.apply_update(update).is_ok());
+        }
+        assert_eq!(server.client_count(), 2);
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(server.evict_idle(std::time::Duration::from_millis(1)), 2);
+        assert_eq!(server.client_count(), 0);
+        // a forgotten client in a version the server still has gets a diff all the same
+        let update = server.get_client_diff(clients[0].update_request());
+        assert!(clients[0].apply_update(update).is_ok());
+    }
+
+    #[test]
+    fn baseline_budget_limits_history() {
+        let mut server = tag_server(Default::default()).with_baseline_budget(250, |_| 100);
+        let mut client: client::Client<Data, u32> = client::Client::with_id(1);
+        let update = server.get_client_diff(client.update_request());
+        assert!(client.apply_update(update).is_ok());
+        for battery in 0..2 {
+            server.state.tags.get_mut(&1).unwrap().battery = battery;
+            server.commit();
+        }
+        // only two copies fit, so the client's version is gone
+        let update = server.get_client_diff(client.update_request());
+        assert!(matches!(update, ClientUpdate::Complete { .. }));
+    }
+
+    #[test]
+    fn stats_count_updates() {
+        let map: ConcMap<u32, u32> = (0..10).map(|i| (i, i)).collect();
+        let server: server::Server<_, u32> = server::Server::new(map)
+            .with_history_len(2)
+            .with_diff_entries(SimpleDiff::len);
+        let mut client: client::Client<ConcMap<u32, u32>, u32> = client::Client::with_id(1);
+        let update = server.get_client_diff(client.update_request());
+        assert!(client.apply_update(update).is_ok());
+        server.state.insert(1, 100);
+        server.state.remove(&2);
+        let diff = server.get_client_diff(client.update_request());
+        assert!(client.apply_update(diff.clone()).is_ok());
+
+        // with a history of two, the client's version is gone once the server moved on twice
+        server.state.insert(3, 300);
+        server.commit();
+        server.state.insert(4, 400);
+        let update = server.get_client_diff(client.update_request());
+        assert!(client.apply_update(update).is_ok());
+        // the earlier diff starts from a state the client has left
+        assert!(client.apply_update(diff).is_err());
+        assert_eq!(client.apply_failures().invalid_update_start_state, 1);
+
+        let stats = server.stats();
+        assert_eq!(stats.sync.requests, 3);
+        assert_eq!(stats.sync.diffs, 1);
+        assert_eq!(stats.sync.diff_entries, 2);
+        assert_eq!(stats.sync.completes_unknown_version, 1);
+        assert_eq!(stats.sync.completes_aged_out, 1);
+        assert_eq!(stats.sync.diff_ratio(), Some(1.0 / 3.0));
+        assert_eq!(stats.clients, 1);
+        assert!(stats.hashes >= 3);
+        assert_eq!(server.client_stats(&1), Some(stats.sync));
+    }
+
+    #[test]
+    fn requests_carry_trace_ids_when_tracing() {
+        let (mut client, mut server) = synced_pair(tag_server(Default::default()));
+        let (first, second) = (client.update_request(), client.update_request());
+        assert_eq!(first.trace_id.is_some(), cfg!(feature = "tracing"));
+        if cfg!(feature = "tracing") {
+            assert_ne!(first.trace_id, second.trace_id);
+        }
+
+        client.state.tags.remove(&1);
+        let push = client.push_request().unwrap();
+        assert_eq!(push.trace_id.is_some(), cfg!(feature = "tracing"));
+        assert!(client
+            .apply_update(server.apply_push(push).unwrap())
+            .is_ok());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn hashes_are_hex_in_json() {
+        let update: ClientUpdate<u32> = ClientUpdate::Diff {
+            diff: 1,
+            newhash: u128::MAX - 1,
+            oldhash: 0xabc,
+            hash_kind: HashKind::XxHash3,
+        };
+        let json = serde_json::to_value(&update).unwrap();
+        assert_eq!(json["Diff"]["newhash"], format!("{:032x}", u128::MAX - 1));
+        assert_eq!(json["Diff"]["oldhash"], "00000000000000000000000000000abc");
+        assert_eq!(
+            serde_json::from_value::<ClientUpdate<u32>>(json).unwrap(),
+            update
+        );
+
+        // numbers are still read, and other formats keep writing them
+        let request: ClientUpdateRequest<u32> =
+            serde_json::from_str(r#"{"id": 1, "current_hash": 42}"#).unwrap();
+        assert_eq!(request.current_hash, 42);
+        let bytes = codec::ContentType::Bincode.encode(&update).unwrap();
+        assert_eq!(
+            codec::ContentType::Bincode
+                .decode::<ClientUpdate<u32>>(&bytes)
+                .unwrap(),
+            update
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn saved_server_keeps_client_baselines() {
+        let map: ConcMap<u32, u32> = (0..10).map(|i| (i, i)).collect();
+        let server = server::Server::new(map)
+            .with_history_len(8)
+            .with_delta_chain(4, SimpleDiff::compose);
+        let (mut client, server) = synced_pair(server);
+        for round in 0..3 {
+            server.state.insert(round, round * 100);
+            server.commit();
+            let update = server.get_client_diff(client.update_request());
+            assert!(client.apply_update(update).is_ok());
+        }
+        server.state.remove(&7);
+
+        let mut saved = Vec::new();
+        server
+            .save_to(&mut saved, codec::ContentType::Json)
+            .unwrap();
+        let loaded: server::Server<ConcMap<u32, u32>, u32> =
+            server::Server::load_from(saved.as_slice())
+                .unwrap()
+                .with_delta_chain(4, SimpleDiff::compose)
+                .with_client_store(DashMap::<u32, server::ClientState>::new());
+        // the loaded clients are moved to the store set after loading
+        assert_eq!(loaded.client_count(), 1);
+        let update = loaded.get_client_diff(client.update_request());
+        assert!(matches!(update, ClientUpdate::Diff { .. }));
+        // applying checks the hash, so the client has the loaded state
+        assert!(client.apply_update(update).is_ok());
+        assert!(client.state.get(&7).is_none());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn periodic_saves_keep_client_baselines() {
+        let path = std::env::temp_dir().join(format!("diffsync-{}.json", std::process::id()));
+        let (mut client, server) = synced_pair(tag_server(Default::default()));
+        let shared = std::sync::Arc::new(std::sync::RwLock::new(server));
+        let (saves_tx, saves_rx) = std::sync::mpsc::channel();
+        let snapshot = shared.clone();
+        let mut saves = 0;
+        server::save_periodically(&path, std::time::Duration::from_millis(10), move || {
+            saves += 1;
+            let _ = saves_tx.send(saves);
+            if saves > 2 {
+                return Err(std::io::ErrorKind::Other.into());
+            }
+            let saved = snapshot.read().unwrap();
+            saved.save_to_vec(codec::ContentType::Json)
+        });
+        // saves are taken one after the other, so the first one is written by now
+        while saves_rx.recv().unwrap() < 2 {}
+
+        let loaded: server::Server<Data, u32> = server::Server::load_file(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded.state, shared.read().unwrap().state);
+        let update = loaded.get_client_diff(client.update_request());
+        assert!(matches!(update, ClientUpdate::Diff { .. }));
+        assert!(client.apply_update(update).is_ok());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn restored_client_gets_diffs() {
+        let (client, mut server) = synced_pair(tag_server(Default::default()));
+        let mut saved = Vec::new();
+        client
+            .save_to(&mut saved, codec::ContentType::Json)
+            .unwrap();
+        drop(client);
+
+        let mut client: client::Client<Data, u32> =
+            client::Client::restore_from(saved.as_slice()).unwrap();
+        assert_eq!(client.id(), 1);
+        server.state.tags.remove(&4);
+        let update = server.get_client_diff(client.update_request());
+        assert!(matches!(update, ClientUpdate::Diff { .. }));
+        assert!(client.apply_update(update).is_ok());
+        assert_eq!(client.state, server.state);
+
+        // a state that doesn't match the saved hash is refused
+        let mut corrupt: serde_json::Value = serde_json::from_slice(&saved[1..]).unwrap();
+        corrupt["baseline_hash"] = 0.into();
+        saved.truncate(1);
+        saved.extend(serde_json::to_vec(&corrupt).unwrap());
+        assert!(client::Client::<Data, u32>::restore_from(saved.as_slice()).is_err());
+    }
+
+    struct FailingStore;
+
+    impl store::ClientStateStore<u32> for FailingStore {
+        fn get(&self, _: &u32) -> std::io::Result<Option<server::ClientState>> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn put(&self, _: u32, _: server::ClientState) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn remove(&self, _: &u32) -> std::io::Result<Option<server::ClientState>> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn for_each(&self, _: &mut dyn FnMut(&u32, &server::ClientState)) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn retain(
+            &self,
+            _: &mut dyn FnMut(&u32, &server::ClientState) -> bool,
+        ) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn len(&self) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn failing_client_store_still_syncs() {
+        let server = tag_server(Default::default())
+            .with_client_store(FailingStore)
+            .with_max_clients(1);
+        let (mut client, mut server) = synced_pair(server);
+        server.state.tags.remove(&3);
+        // the server can't remember any client, but a client in a committed version still
+        // gets a diff from it
+        let update = server.get_client_diff(client.update_request());
+        assert!(matches!(update, ClientUpdate::Diff { .. }));
+        assert!(client.apply_update(update).is_ok());
+        assert_eq!(client.state, server.state);
+        assert_eq!(server.client_count(), 0);
+    }
+
+    /// Requests of clients 0 to 3, with client 1 coming back before 3 is first seen
+    fn assert_evicts_least_recently_used(server: server::Server<Data, u32>) {
+        let server = server.with_max_clients(2);
+        let mut clients: Vec<client::Client<Data, u32>> =
+            (0..4).map(client::Client::with_id).collect();
+        for id in [0, 1, 2, 1, 3] {
+            let client = &mut clients[id as usize];
+            let update = server.get_client_diff(client.update_request());
+            assert!(client.apply_update(update).is_ok());
+        }
+        let kept: Vec<u32> = (0..4)
+            .filter(|id| server.client_stats(id).is_some())
+            .collect();
+        assert_eq!(kept, [1, 3]);
+        assert_eq!(server.client_count(), 2);
+    }
+
+    #[test]
+    fn least_recently_used_clients_are_evicted() {
+        assert_evicts_least_recently_used(
+            tag_server(Default::default())
+                .with_client_store(DashMap::<u32, server::ClientState>::new()),
+        );
+    }
+
+    #[cfg(feature = "redb")]
+    #[test]
+    fn least_recently_used_clients_are_evicted_on_disk() {
+        let path =
+            std::env::temp_dir().join(format!("diffsync-eviction-{}.redb", std::process::id()));
+        assert_evicts_least_recently_used(
+            tag_server(Default::default())
+                .with_client_store(store::RedbStore::open(&path).unwrap()),
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "redb")]
+    #[test]
+    fn client_states_on_disk() {
+        let path = std::env::temp_dir().join(format!("diffsync-{}.redb", std::process::id()));
+        let server = tag_server(Default::default())
+            .with_client_store(store::RedbStore::open(&path).unwrap());
+        let (mut client, mut server) = synced_pair(server);
+        server.state.tags.remove(&3);
+        let update = server.get_client_diff(client.update_request());
+        assert!(client.apply_update(update).is_ok());
+        assert_eq!(server.client_count(), 1);
+        assert_eq!(server.client_stats(&1).unwrap().requests, 2);
+        server.forget_client(1);
+        assert_eq!(server.client_count(), 0);
+        drop(server);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn tag_server(policy: conflict::ConflictPolicy<Data>) -> server::Server<Data, u32> {
+        let mut rng = ThreadRng::default();
+        let mut server = server::Server::default().with_conflict_policy(policy);
+        for i in 0..10 {
+            server.state.tags.insert(i, Tag::random_variant(&mut rng));
+        }
+        server
+    }
+
+    #[test]
+    fn push_merges_changes() {
+        let (mut client, mut server) =
+            synced_pair(tag_server(conflict::ConflictPolicy::merge(|_| false)));
+        assert!(client.push_request().is_none());
+
+        // clients that only follow the server keep no baseline to push from
+        let mut follower: client::Client<Data, u32> = client::Client::with_id(2);
+        follower.state.tags.insert(1, Tag::default());
+        assert!(follower.has_local_changes());
+        assert!(follower.push_request().is_none());
+
+        client.state.tags.get_mut(&1).unwrap().battery = 1;
+        client.state.tags.remove(&2);
+        let client_update = server.apply_push(client.push_request().unwrap()).unwrap();
+        assert!(client.apply_update(client_update).is_ok());
+        assert_eq!(client.state, server.state);
+        assert!(!client.has_local_changes());
+        assert_eq!(server.state.tags[&1].battery, 1);
+        assert!(!server.state.tags.contains_key(&2));
+
+        // the server has changed since, the changes of both are kept, and a value changed by
+        // both is taken from the server
+        client.state.tags.get_mut(&3).unwrap().battery = 3;
+        client.state.tags.get_mut(&5).unwrap().battery = 12;
+        server.state.tags.get_mut(&4).unwrap().battery = 44;
+        server.state.tags.get_mut(&5).unwrap().battery = 15;
+        let client_update = server.apply_push(client.push_request().unwrap()).unwrap();
+        assert!(client.apply_update(client_update).is_ok());
+        assert_eq!(client.state, server.state);
+        assert_eq!(server.state.tags[&3].battery, 3);
+        assert_eq!(server.state.tags[&4].battery, 44);
+        assert_eq!(server.state.tags[&5].battery, 15);
+    }
+
+    #[test]
+    fn push_conflict_policies() {
+        // once both sides have changed, the state of the winning side is kept whole, and a number
+        // changed by both is never added up
+        let conflicting = |policy, client_later| {
+            let mut server = tag_server(policy);
+            server.state.tags.get_mut(&1).unwrap().battery = 10;
+            let (mut client, mut server) = synced_pair(server);
+            let push = if client_later {
+                server.state.tags.get_mut(&1).unwrap().battery = 15;
+                server.state.tags.get_mut(&4).unwrap().battery = 4444;
+                server.commit();
+                std::thread::sleep(std::time::Duration::from_millis(2));
+                client.state.tags.get_mut(&1).unwrap().battery = 12;
+                client.state.tags.get_mut(&3).unwrap().battery = 3333;
+                client.push_request().unwrap()
+            } else {
+                client.state.tags.get_mut(&1).unwrap().battery = 12;
+                client.state.tags.get_mut(&3).unwrap().battery = 3333;
+                let push = client.push_request().unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(2));
+                server.state.tags.get_mut(&1).unwrap().battery = 15;
+                server.state.tags.get_mut(&4).unwrap().battery = 4444;
+                push
+            };
+            let client_update = server.apply_push(push).unwrap();
+            assert!(client.apply_update(client_update).is_ok());
+            assert_eq!(client.state, server.state);
+            let battery = |id| server.state.tags[&id].battery;
+            (battery(1), battery(3) == 3333, battery(4) == 4444)
+        };
+        use conflict::ConflictPolicy;
+        assert_eq!(
+            conflicting(ConflictPolicy::ServerWins, true),
+            (15, false, true)
+        );
+        assert_eq!(
+            conflicting(ConflictPolicy::ClientWins, false),
+            (12, true, false)
+        );
+        assert_eq!(
+            conflicting(ConflictPolicy::LastWriterWins, true),
+            (12, true, false)
+        );
+        assert_eq!(
+            conflicting(ConflictPolicy::LastWriterWins, false),
+            (15, false, true)
+        );
+
+        // merging keeps what only one side changed, and takes what both changed whole
+        let merge = || ConflictPolicy::merge(|conflict| conflict.client_is_newer());
+        assert_eq!(conflicting(merge(), true), (12, true, true));
+        assert_eq!(conflicting(merge(), false), (15, true, true));
+
+        let (mut client, mut server) = synced_pair(tag_server(conflict::ConflictPolicy::Custom(
+            Box::new(|conflict| {
+                let mut merged = conflict.server.clone();
+                merged.tags.clear();
+                merged
+            }),
+        )));
+        client.state.tags.get_mut(&3).unwrap().battery = 3;
+        let client_update = server.apply_push(client.push_request().unwrap()).unwrap();
+        assert!(client.apply_update(client_update).is_ok());
+        assert!(server.state.tags.is_empty());
+        assert_eq!(client.state, server.state);
+    }
+
+    #[test]
+    fn rejected_pushes() {
+        // a push based on a version the server no longer has is rejected, and merged once the
+        // client attaches that version
+        let server = tag_server(conflict::ConflictPolicy::merge(|_| false)).with_history_len(1);
+        let (mut client, mut server) = synced_pair(server);
+        client.state.tags.get_mut(&3).unwrap().battery = 3333;
+        for battery in 0..3 {
+            server.state.tags.get_mut(&4).unwrap().battery = battery;
+            server.commit();
+        }
+        let error = server
+            .apply_push(client.push_request().unwrap())
+            .err()
+            .unwrap();
+        assert!(matches!(error, PushError::UnknownBase { .. }));
+        client.push_rejected(&error);
+        let client_update = server.apply_push(client.push_request().unwrap()).unwrap();
+        assert!(client.apply_update(client_update).is_ok());
+        assert_eq!(client.state, server.state);
+        assert_eq!(server.state.tags[&3].battery, 3333);
+        assert_eq!(server.state.tags[&4].battery, 2);
+
+        // a diff that doesn't fit its base is rejected instead of panicking the server
+        let (mut client, mut server) = synced_pair(server::Server::new(vec![1u32, 2, 3]));
+        client.state.push(4);
+        let mut push = client.push_request().unwrap();
+        push.diff = VecDiff(vec![VecDiffType::Removed { index: 10, len: 2 }]);
+        let error = server.apply_push(push).unwrap_err();
+        assert!(matches!(error, PushError::InvalidPush { .. }));
+        assert_eq!(server.state, [1, 2, 3]);
+
+        // as is an attached version that doesn't have the hash the push claims
+        let mut push = client.push_request().unwrap();
+        push.base_hash += 1;
+        push.base = Some(Vec::<u32>::new().diff(&vec![5]));
+        let error = server.apply_push(push).unwrap_err();
+        assert!(matches!(error, PushError::InvalidPush { .. }));
+
+        // the client gives up on its changes
+        client.push_rejected(&error);
+        assert!(client.push_request().is_none());
+        let update = server.get_client_diff(client.update_request());
+        assert!(matches!(update, ClientUpdate::Complete { .. }));
+        assert!(client.apply_update(update).is_ok());
+        assert_eq!(client.state, [1, 2, 3]);
+    }
+
+    #[test]
+    fn push_last_writer_wins_per_key() {
+        let map: ConcMap<u32, u32> = ConcMap::default();
+        for i in 0..10 {
+            map.insert(i, i);
+        }
+        let server = server::Server::new(map)
+            .with_conflict_policy(conflict::ConflictPolicy::last_writer_wins_per_key());
+        let (mut client, mut server) = synced_pair(server);
+
+        client.state.insert(1, 100);
+        client.state.insert(2, 200);
+        client.state.remove(&3);
+        server.state.insert(2, 2000);
+        server.state.insert(4, 4000);
+        server.commit();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+
+        let client_update = server.apply_push(client.push_request().unwrap()).unwrap();
+        assert!(client.apply_update(client_update).is_ok());
+        assert_eq!(client.state.as_btree(), server.state.as_btree());
+        assert_eq!(*server.state.get(&1).unwrap(), 100);
+        assert_eq!(*server.state.get(&2).unwrap(), 200);
+        assert!(server.state.get(&3).is_none());
+        assert_eq!(*server.state.get(&4).unwrap(), 4000);
+    }
 }
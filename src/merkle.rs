@@ -0,0 +1,297 @@
+//! Reconciliation of keyed maps by comparing hashes over key ranges, for clients whose state
+//! the server doesn't know, but which likely have most of it already. For example after a
+//! server restart, or after [`crate::server::Server::forget_client`].
+//!
+//! The client starts with the hash of its whole map. The server answers every range whose hash
+//! differs from its own either with its entries in that range, if there are few enough, or by
+//! splitting the range into [`FANOUT`] smaller ranges along its own keys and sending their
+//! hashes. The client compares those with its own, and asks again for the ones that differ.
+//! Only entries in differing ranges are ever transferred, and the number of round trips grows
+//! with the logarithm of the map size.
+//!
+//! See [`crate::client::Client::reconcile_request`] and
+//! [`crate::server::Server::reconcile`]. Once a client is done it has the server state, so its
+//! next update request is answered with a diff.
+
+use std::{borrow::Cow, ops::Bound};
+
+use crate::customhash::CustomHash;
+
+use super::*;
+
+/// Ranges with at most this many entries on the server are sent as entries instead of being
+/// split further
+pub const LEAF_LEN: usize = 32;
+
+/// How many ranges a differing range is split into
+pub const FANOUT: usize = 16;
+
+/// A map that can be reconciled
+pub trait MerkleMap {
+    type Key: Ord + Clone + Hash;
+    type Value: Clone + Hash;
+
+    /// The entries in key order. Taken once per request or response, so maps without an order
+    /// sort only once however many ranges they are asked about
+    fn ordered(&self) -> Cow<'_, BTreeMap<Self::Key, Self::Value>>;
+
+    /// Replace everything within the range with the entries
+    fn replace_range(
+        &mut self,
+        range: &KeyRange<Self::Key>,
+        entries: Vec<(Self::Key, Self::Value)>,
+    );
+}
+
+/// The keys from `start` up to but not including `end`, `None` meaning unbounded
+#[cfg_attr(feature = "impl_schemars", derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyRange<K> {
+    pub start: Option<K>,
+    pub end: Option<K>,
+}
+
+impl<K: Ord> KeyRange<K> {
+    /// Every key
+    pub fn full() -> Self {
+        Self {
+            start: None,
+            end: None,
+        }
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.start.as_ref().is_none_or(|start| key >= start)
+            && self.end.as_ref().is_none_or(|end| key < end)
+    }
+
+    fn bounds(&self) -> (Bound<&K>, Bound<&K>) {
+        (
+            self.start
+                .as_ref()
+                .map_or(Bound::Unbounded, Bound::Included),
+            self.end.as_ref().map_or(Bound::Unbounded, Bound::Excluded),
+        )
+    }
+}
+
+/// Hash of the entries of a map within a range
+#[cfg_attr(feature = "impl_schemars", derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RangeHash<K> {
+    pub range: KeyRange<K>,
+    #[serde(with = "customhash::hex")]
+    #[cfg_attr(feature = "impl_schemars", schemars(with = "String"))]
+    pub hash: StateHash,
+    pub len: u64,
+}
+
+/// The entries of the server within a range, replacing whatever the client has there
+#[cfg_attr(feature = "impl_schemars", derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RangeEntries<K, V> {
+    pub range: KeyRange<K>,
+    pub entries: Vec<(K, V)>,
+}
+
+/// The ranges where the client wants to know whether the server differs
+#[cfg_attr(feature = "impl_schemars", derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReconcileRequest<K> {
+    pub hash_kind: HashKind,
+    pub ranges: Vec<RangeHash<K>>,
+}
+
+/// Answer to a [`ReconcileRequest`], entries for small differing ranges, and hashes of the
+/// pieces of large ones
+#[cfg_attr(feature = "impl_schemars", derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReconcileResponse<K, V> {
+    pub hash_kind: HashKind,
+    pub ranges: Vec<RangeHash<K>>,
+    pub entries: Vec<RangeEntries<K, V>>,
+}
+
+/// Hash the entries of the map within the range
+pub fn range_hash<A: HashAlgorithm, M: MerkleMap>(
+    map: &M,
+    range: KeyRange<M::Key>,
+) -> RangeHash<M::Key> {
+    hash_entries::<A, _, _>(&map.ordered(), range)
+}
+
+fn hash_entries<A: HashAlgorithm, K: Ord + Hash, V: Hash>(
+    entries: &BTreeMap<K, V>,
+    range: KeyRange<K>,
+) -> RangeHash<K> {
+    let mut hasher = CustomHash::<A>::default();
+    let mut len = 0;
+    for (key, value) in entries.range::<K, _>(range.bounds()) {
+        key.hash(&mut hasher);
+        value.hash(&mut hasher);
+        len += 1;
+    }
+    RangeHash {
+        range,
+        hash: hasher.finish128(),
+        len,
+    }
+}
+
+/// The request a client starts reconciling with
+pub fn start<A: HashAlgorithm, M: MerkleMap>(map: &M) -> ReconcileRequest<M::Key> {
+    ReconcileRequest {
+        hash_kind: A::KIND,
+        ranges: vec![range_hash::<A, M>(map, KeyRange::full())],
+    }
+}
+
+/// Answer a request on the server side
+pub fn respond<A: HashAlgorithm, M: MerkleMap>(
+    map: &M,
+    request: ReconcileRequest<M::Key>,
+) -> ReconcileResponse<M::Key, M::Value> {
+    let map = map.ordered();
+    let mut response = ReconcileResponse {
+        hash_kind: A::KIND,
+        ranges: Vec::new(),
+        entries: Vec::new(),
+    };
+    if request.hash_kind != A::KIND {
+        // nothing would ever match, so start over with the whole map
+        log::warn!(
+            "Reconciling with {:?} hashes, but the server uses {:?}",
+            request.hash_kind,
+            A::KIND
+        );
+        response
+            .ranges
+            .push(hash_entries::<A, _, _>(&map, KeyRange::full()));
+        return response;
+    }
+
+    for theirs in request.ranges {
+        let ours = hash_entries::<A, _, _>(&map, theirs.range.clone());
+        if ours.hash == theirs.hash && ours.len == theirs.len {
+            continue;
+        }
+        if ours.len as usize <= LEAF_LEN {
+            let entries = map
+                .range::<M::Key, _>(ours.range.bounds())
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            response.entries.push(RangeEntries {
+                range: ours.range,
+                entries,
+            });
+            continue;
+        }
+        for range in split(&map, ours) {
+            response.ranges.push(hash_entries::<A, _, _>(&map, range));
+        }
+    }
+    response
+}
+
+/// Split a range into [`FANOUT`] ranges holding about as many of our keys each
+fn split<K: Ord + Clone, V>(entries: &BTreeMap<K, V>, range: RangeHash<K>) -> Vec<KeyRange<K>> {
+    let step = (range.len as usize).div_ceil(FANOUT).max(1);
+    let bounds: Vec<K> = entries
+        .range::<K, _>(range.range.bounds())
+        .map(|(key, _)| key)
+        .step_by(step)
+        .skip(1)
+        .cloned()
+        .collect();
+
+    let mut ranges = Vec::with_capacity(bounds.len() + 1);
+    let mut start = range.range.start;
+    for bound in bounds {
+        ranges.push(KeyRange {
+            start: start.replace(bound.clone()),
+            end: Some(bound),
+        });
+    }
+    ranges.push(KeyRange {
+        start,
+        end: range.range.end,
+    });
+    ranges
+}
+
+/// Apply a response on the client side, returning the next request, or `None` if the map now
+/// matches the server
+pub fn apply<A: HashAlgorithm, M: MerkleMap>(
+    map: &mut M,
+    response: ReconcileResponse<M::Key, M::Value>,
+) -> Option<ReconcileRequest<M::Key>> {
+    if response.hash_kind != A::KIND {
+        // the server can't help, the next update request gets a complete update instead
+        log::warn!(
+            "Reconciling with {:?} hashes, but the server uses {:?}",
+            A::KIND,
+            response.hash_kind
+        );
+        return None;
+    }
+    for RangeEntries { range, entries } in response.entries {
+        map.replace_range(&range, entries);
+    }
+    let ordered = map.ordered();
+    let ranges: Vec<_> = response
+        .ranges
+        .into_iter()
+        .map(|theirs| {
+            (
+                hash_entries::<A, _, _>(&ordered, theirs.range.clone()),
+                theirs,
+            )
+        })
+        .filter(|(ours, theirs)| ours.hash != theirs.hash || ours.len != theirs.len)
+        .map(|(ours, _)| ours)
+        .collect();
+    (!ranges.is_empty()).then_some(ReconcileRequest {
+        hash_kind: A::KIND,
+        ranges,
+    })
+}
+
+impl<K: Ord + Clone + Hash, V: Clone + Hash> MerkleMap for BTreeMap<K, V> {
+    type Key = K;
+    type Value = V;
+
+    fn ordered(&self) -> Cow<'_, BTreeMap<K, V>> {
+        Cow::Borrowed(self)
+    }
+
+    fn replace_range(&mut self, range: &KeyRange<K>, entries: Vec<(K, V)>) {
+        self.retain(|key, _| !range.contains(key));
+        self.extend(entries);
+    }
+}
+
+impl<K, V, H> MerkleMap for ConcMap<K, V, H>
+where
+    K: Ord + Clone + Hash,
+    V: PartialEq + Clone + Hash,
+    H: HashAlgorithm,
+{
+    type Key = K;
+    type Value = V;
+
+    fn ordered(&self) -> Cow<'_, BTreeMap<K, V>> {
+        // dashmap has no order, so the entries are collected and sorted
+        Cow::Owned(
+            self.iter()
+                .map(|r| (r.key().clone(), r.value().clone()))
+                .collect(),
+        )
+    }
+
+    fn replace_range(&mut self, range: &KeyRange<K>, entries: Vec<(K, V)>) {
+        self.retain(|key, _| !range.contains(key));
+        for (key, value) in entries {
+            self.insert(key, value);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Per client views of the server state. Each client is sent a view projected from the state
+//! for that client only, so it never receives anything outside of its view. Clients are plain
+//! [`crate::client::Client`]s with the view as their state.
+//!
+//! The server keeps the clients of a projection like its own, in its
+//! [`crate::store::ClientStateStore`], counted in its stats and subject to its limits and
+//! persistence, so their ids must not clash with the ids of clients syncing the whole state.
+//! What is kept is the version of the state a client's view was projected from, along with the
+//! hash of the view, and the view is projected again from that version to diff against. Acks
+//! go to [`Server::acknowledge`] as usual.
+//!
+//! Views can't be pushed back, as there is no general way to merge a view into the state.
+
+use crate::{server::Server, trace::TracedId};
+
+use super::*;
+
+/// Projects a view for every client
+pub type ProjectFn<STATE, ID, VIEW> = Box<dyn Fn(&ID, &STATE) -> VIEW + Send + Sync>;
+
+/// Answers update requests of clients with their own view of a [`Server`]'s state
+pub struct Projection<STATE, ID, VIEW> {
+    project: ProjectFn<STATE, ID, VIEW>,
+}
+
+impl<STATE, ID, VIEW> Projection<STATE, ID, VIEW> {
+    pub fn new(project: impl Fn(&ID, &STATE) -> VIEW + Send + Sync + 'static) -> Self {
+        Self {
+            project: Box::new(project),
+        }
+    }
+}
+
+impl<STATE, ID, VIEW> Projection<STATE, ID, VIEW>
+where
+    STATE: Hash + Clone + Diff,
+    ID: Hash + Ord + TracedId,
+    VIEW: Hash + Diff,
+{
+    /// Like [`Server::get_client_diff`], but diffing the view of the requesting client
+    pub fn get_client_diff<H: HashAlgorithm>(
+        &self,
+        server: &Server<STATE, ID, H>,
+        request: ClientUpdateRequest<ID>,
+    ) -> ClientUpdate<VIEW::Repr> {
+        server.get_view_diff(request, &self.project)
+    }
+}
@@ -1,34 +1,280 @@
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fmt::Debug,
+    fs,
+    io::{self, Read, Write},
+    marker::PhantomData,
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
 use dashmap::DashMap;
-use twox_hash::XxHash64;
 
-use crate::customhash::CustomHash;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    codec::{self, ContentType},
+    conflict::{now_millis, Conflict, ConflictPolicy},
+    customhash::XxHash64,
+    merkle::{self, MerkleMap, ReconcileRequest, ReconcileResponse},
+    stats::{CompleteReason, ServerCounters, ServerStats, SyncCounters, SyncStats},
+    store::ClientStateStore,
+    trace::{trace_event, TracedId},
+};
 
 use super::*;
 
-#[derive(Default)]
-pub struct Server<STATE, ID>
+/// Number of committed versions kept by default
+pub const DEFAULT_HISTORY_LEN: usize = 16;
+
+/// Keeps track of what every client knows of the state. Clients have to hash with the same
+/// [`HashAlgorithm`] as the server
+pub struct Server<STATE, ID, H = XxHash64>
 where
     STATE: Diff,
     ID: Hash + Ord,
 {
     pub state: STATE,
-    // Keep states
-    client_states: DashMap<ID, ClientState<STATE>>,
+    // Committed versions of the state, shared between all clients
+    history: RwLock<History<STATE>>,
+    // What the server knows of every client
+    client_states: Box<dyn ClientStateStore<ID>>,
+    conflict_policy: ConflictPolicy<STATE>,
+    eviction: Eviction,
+    stats: ServerCounters,
+    diff_entries: Option<fn(&STATE::Repr) -> usize>,
+    hasher: PhantomData<fn() -> H>,
+}
+
+impl<STATE: Diff + Default, ID: Hash + Ord + Send + Sync + 'static, H> Default
+    for Server<STATE, ID, H>
+{
+    fn default() -> Self {
+        Self::new(STATE::default())
+    }
 }
 
-impl<STATE: Diff, ID: Hash + Ord> Server<STATE, ID> {
+impl<STATE: Diff, ID: Hash + Ord, H> Server<STATE, ID, H> {
     /// Create a new server instance using the supplied data
-    pub fn new(data: STATE) -> Self {
+    pub fn new(data: STATE) -> Self
+    where
+        ID: Send + Sync + 'static,
+    {
         Self {
             state: data,
-            client_states: Default::default(),
+            history: RwLock::new(History::new(DEFAULT_HISTORY_LEN)),
+            client_states: Box::new(DashMap::<ID, ClientState>::new()),
+            conflict_policy: Default::default(),
+            eviction: Eviction::new(),
+            stats: Default::default(),
+            diff_entries: None,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Set how changes pushed by clients are merged with changes made on the server, defaults to
+    /// [`ConflictPolicy::ServerWins`]
+    pub fn with_conflict_policy(mut self, policy: ConflictPolicy<STATE>) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
+
+    /// Set how many committed versions of the state the server keeps around to diff against.
+    /// Clients whose last known version has been dropped from the history get a complete update
+    pub fn with_history_len(self, len: usize) -> Self {
+        self.history
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_max_len(len);
+        self
+    }
+
+    /// Keep only the diff from the previous version for most committed versions, instead of a
+    /// copy of the state, so that the memory used by the history grows with the size of the
+    /// changes rather than the size of the state. Every `keyframe_interval` versions a copy is
+    /// kept anyway, to rebuild older versions from.
+    ///
+    /// `compose` has to combine two diffs into one with the same effect as applying both in
+    /// order, like [`crate::SimpleDiff::compose`]. Clients are sent the composed diffs since
+    /// their version, or when they are further behind, the diff against their version rebuilt
+    /// from the nearest keyframe before it
+    pub fn with_delta_chain(
+        self,
+        keyframe_interval: usize,
+        compose: fn(&STATE::Repr, &STATE::Repr) -> STATE::Repr,
+    ) -> Self {
+        let mut history = self.history.write().unwrap_or_else(PoisonError::into_inner);
+        history.storage = Storage::Deltas {
+            keyframe_interval: keyframe_interval.max(1),
+            compose,
+        };
+        drop(history);
+        self
+    }
+
+    /// Forget clients that haven't sent anything for longer than `timeout`. Checked every now
+    /// and then while answering requests, see also [`Server::evict_idle`]
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.eviction.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Keep at most this many clients, forgetting the least recently seen ones
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.eviction.max_clients = Some(max_clients.max(1));
+        self
+    }
+
+    /// Drop the oldest versions from the history while the copies of the state kept as
+    /// baselines take up more than `bytes`, as estimated by `size_of`. The latest version is
+    /// always kept
+    pub fn with_baseline_budget(self, bytes: usize, size_of: SizeFn<STATE>) -> Self {
+        let mut history = self.history.write().unwrap_or_else(PoisonError::into_inner);
+        history.budget = Some((bytes, size_of));
+        for snapshot in &mut history.versions {
+            snapshot.size = snapshot.state.as_deref().map_or(0, size_of);
         }
+        drop(history);
+        self
+    }
+
+    /// Keep the client states in the given store instead of in memory, see [`crate::store`].
+    /// Clients already known to the server, like the ones of a loaded server, are moved to the
+    /// new store, replacing any it has with the same id
+    pub fn with_client_store(mut self, store: impl ClientStateStore<ID> + 'static) -> Self
+    where
+        ID: Clone,
+    {
+        let moved = self.client_states.for_each(&mut |id, clientstate| {
+            if let Err(e) = store.put(id.clone(), clientstate.clone()) {
+                log::warn!("Failed to move a client state to the new store: {e}");
+            }
+        });
+        if let Err(e) = moved {
+            log::warn!("Failed to move the client states to the new store: {e}");
+        }
+        self.client_states = Box::new(store);
+        self
+    }
+
+    /// Count the entries of every diff sent, for [`Server::stats`], like
+    /// [`crate::SimpleDiff::len`]
+    pub fn with_diff_entries(mut self, count: fn(&STATE::Repr) -> usize) -> Self {
+        self.diff_entries = Some(count);
+        self
+    }
+
+    /// Counters of all clients together
+    pub fn stats(&self) -> ServerStats {
+        self.stats.snapshot(self.client_count())
+    }
+
+    /// Counters of a single client, for as long as the server keeps track of it
+    pub fn client_stats(&self, id: &ID) -> Option<SyncStats> {
+        self.load_client(id)
+            .map(|clientstate| clientstate.stats.snapshot())
     }
 
-    /// Allows for the server to forget a client. For example, one might keep track of when a client
-    /// last requested an update, and remove it if that was too long ago
+    /// Record the encoded size of an update sent to a client, for transports to call
+    pub fn record_encoded_update(&self, bytes: usize) {
+        self.stats.encoded(bytes);
+    }
+
+    /// Allows for the server to forget a client, it is treated as a new client on its next request.
+    /// See also [`Server::with_idle_timeout`] and [`Server::with_max_clients`]
     pub fn forget_client(&mut self, id: ID) {
-        self.client_states.remove(&id);
+        if let Err(e) = self.client_states.remove(&id) {
+            log::warn!("Failed to forget a client: {e}");
+        }
+    }
+
+    /// Number of clients the server keeps track of
+    pub fn client_count(&self) -> usize {
+        self.client_states.len().unwrap_or_else(|e| {
+            log::warn!("Failed to count clients: {e}");
+            0
+        })
+    }
+
+    /// The ids of the clients the server keeps track of
+    pub(crate) fn client_ids(&self) -> BTreeSet<ID>
+    where
+        ID: Clone,
+    {
+        let mut ids = BTreeSet::new();
+        let listed = self.client_states.for_each(&mut |id, _| {
+            ids.insert(id.clone());
+        });
+        if let Err(e) = listed {
+            log::warn!("Failed to list clients: {e}");
+        }
+        ids
+    }
+
+    /// Forget every client that hasn't sent anything for longer than `max_idle`, returning how
+    /// many were forgotten
+    pub fn evict_idle(&self, max_idle: Duration) -> usize {
+        let oldest = now_millis().saturating_sub(max_idle.as_millis() as u64);
+        self.client_states
+            .retain(&mut |_, clientstate| clientstate.last_seen >= oldest)
+            .unwrap_or_else(|e| {
+                log::warn!("Failed to evict idle clients: {e}");
+                0
+            })
+    }
+
+    /// The state of a client, a client that fails to load is treated as unknown
+    fn load_client(&self, id: &ID) -> Option<ClientState> {
+        self.client_states.get(id).unwrap_or_else(|e| {
+            log::warn!("Failed to load a client state: {e}");
+            None
+        })
+    }
+
+    fn store_client(&self, id: ID, clientstate: ClientState) {
+        if let Err(e) = self.client_states.put(id, clientstate) {
+            log::warn!("Failed to store a client state, it gets a complete update next: {e}");
+        }
+    }
+
+    /// Note that a client sent something, and return its state to be stored again
+    fn touch_client(&self, id: &ID) -> ClientState {
+        let mut clientstate = self.load_client(id).unwrap_or_default();
+        clientstate.last_seen = now_millis();
+        clientstate.last_use = self.eviction.next_use();
+        clientstate
+    }
+
+    /// Apply the configured limits, must not be called while holding a client state
+    fn evict(&self) {
+        if let Some(timeout) = self.eviction.sweep_due() {
+            self.evict_idle(timeout);
+        }
+        if let Some(max_clients) = self.eviction.max_clients {
+            if self.client_count() > max_clients {
+                if let Err(e) = self.evict_least_recently_used(max_clients) {
+                    log::warn!("Failed to evict the least recently used clients: {e}");
+                }
+            }
+        }
+    }
+
+    fn evict_least_recently_used(&self, max_clients: usize) -> std::io::Result<()> {
+        let mut uses = Vec::new();
+        self.client_states
+            .for_each(&mut |_, clientstate| uses.push(clientstate.last_use))?;
+        let Some(oldest_kept) = oldest_kept(&mut uses, max_clients) else {
+            return Ok(());
+        };
+        self.client_states
+            .retain(&mut |_, clientstate| clientstate.last_use >= oldest_kept)?;
+        Ok(())
     }
 
     /// Get access to the server state data for reading
@@ -42,59 +288,839 @@ impl<STATE: Diff, ID: Hash + Ord> Server<STATE, ID> {
     }
 }
 
-impl<STATE: Hash + Clone + Diff, ID: Hash + Ord> Server<STATE, ID> {
-    fn calculate_hash(&self) -> u64 {
-        let mut h = CustomHash::new();
-        self.state.hash(&mut h);
-        h.finish()
+impl<STATE: Hash + Clone + Diff, ID: Hash + Ord + TracedId, H: HashAlgorithm> Server<STATE, ID, H> {
+    fn calculate_hash(&self) -> StateHash {
+        let started = Instant::now();
+        let hash = H::hash_of(&self.state);
+        self.stats.hashed(started.elapsed());
+        hash
+    }
+
+    /// Record the current state as the latest version. This is done automatically whenever a
+    /// client requests an update, but committing right after modifying the state gives a more
+    /// accurate write time for [`ConflictPolicy::LastWriterWins`]
+    pub fn commit(&self) {
+        self.commit_hash(self.calculate_hash());
+    }
+
+    /// Make sure the state with the given hash is the latest version in the history
+    fn commit_hash(&self, hash: StateHash) -> VersionRef {
+        if let Some(head) = self.read_history().head() {
+            if head.hash == hash {
+                return head;
+            }
+        }
+        let mut history = self.history.write().unwrap_or_else(PoisonError::into_inner);
+        // someone else might have committed while we were waiting for the lock
+        match history.head() {
+            Some(head) if head.hash == hash => head,
+            _ => history.push(Arc::new(self.state.clone()), hash),
+        }
+    }
+
+    fn read_history(&self) -> std::sync::RwLockReadGuard<'_, History<STATE>> {
+        self.history.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Confirm that the client has applied the update it was last sent, so that it will be used
+    /// as the baseline for the next diff. Acks for unknown clients or hashes are ignored, the
+    /// client will get a complete update on its next request instead
+    pub fn acknowledge(&self, ack: ClientAck<ID>) {
+        self.stats.ack();
+        if let Some(mut clientstate) = self.load_client(&ack.id) {
+            clientstate.stats.ack();
+            clientstate.last_seen = now_millis();
+            clientstate.confirm(ack.hash);
+            self.store_client(ack.id, clientstate);
+        }
     }
 
     pub fn get_client_diff(&self, request: ClientUpdateRequest<ID>) -> ClientUpdate<STATE::Repr> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "get_client_diff",
+            client = ?request.id,
+            trace_id = request.trace_id,
+            oldhash = %crate::trace::Hex(request.current_hash),
+            newhash = tracing::field::Empty,
+            kind = tracing::field::Empty,
+            diff_entries = tracing::field::Empty,
+        )
+        .entered();
+        if request.hash_kind != H::KIND {
+            // the hash can't match anything and the client refuses any update, so don't bother
+            // with its state, an empty diff is enough for it to report the mismatch
+            log::warn!(
+                "Client hashes with {:?}, but the server with {:?}",
+                request.hash_kind,
+                H::KIND
+            );
+            return ClientUpdate::Diff {
+                diff: STATE::identity().diff(&STATE::identity()),
+                newhash: request.current_hash,
+                oldhash: request.current_hash,
+                hash_kind: H::KIND,
+            };
+        }
         let serverhash = self.calculate_hash();
+        let head = self.commit_hash(serverhash);
 
-        let upd = match self.client_states.get(&request.id) {
-            Some(clientstate) => {
-                // we know of this client, we know of a state that was last request, we need to verify that the clients current state is the one we have
-                if clientstate.hash == request.current_hash {
-                    ClientUpdate::Diff {
-                        diff: clientstate.state.diff(&self.state),
-                        newhash: serverhash,
-                        oldhash: request.current_hash,
-                    }
-                } else {
-                    let new: STATE = STATE::identity();
-                    let complete_diff = new.diff(&self.state);
-                    // send complete new update if the clients percieced hash and what the server thinks the client has differs
-                    ClientUpdate::Complete {
-                        complete_diff,
-                        newhash: serverhash,
-                    }
+        let mut clientstate = self.touch_client(&request.id);
+
+        // A request with the hash of the pending baseline doubles as an ack for it. If the hash
+        // matches the confirmed baseline instead, the last response never made it to the client
+        // and we simply diff against what it confirmed earlier
+        clientstate.confirm(request.current_hash);
+
+        self.stats.request();
+        clientstate.stats.request();
+
+        let baseline = if request.force_complete {
+            Err(CompleteReason::Requested)
+        } else {
+            let history = self.read_history();
+            let version = match clientstate.confirmed {
+                Some(confirmed) if confirmed.hash == request.current_hash => {
+                    Some(confirmed).filter(|&confirmed| history.contains(confirmed))
+                }
+                // the client might still be in a state we know about, even if we don't know the client
+                _ => history.find_hash(request.current_hash),
+            };
+            match version {
+                Some(version) => history
+                    .baseline(version, head)
+                    .map(|baseline| (version, baseline))
+                    .ok_or(CompleteReason::TooFarBehind),
+                None if clientstate
+                    .confirmed
+                    .is_some_and(|c| c.hash == request.current_hash) =>
+                {
+                    Err(CompleteReason::AgedOut)
+                }
+                None => Err(CompleteReason::UnknownVersion),
+            }
+        };
+
+        let upd = match baseline {
+            Ok((version, baseline)) => {
+                clientstate.confirmed = Some(version);
+                let diff = match baseline {
+                    Baseline::State(state) => STATE::diff(&state, &self.state),
+                    Baseline::Chain(compose, diffs) => diffs.iter().fold(
+                        STATE::identity().diff(&STATE::identity()),
+                        |composed, diff| compose(&composed, diff),
+                    ),
+                };
+                let entries = self.diff_entries.map(|count| count(&diff));
+                #[cfg(feature = "tracing")]
+                span.record("diff_entries", entries);
+                self.stats.diff(entries);
+                clientstate.stats.diff(entries);
+                ClientUpdate::Diff {
+                    diff,
+                    newhash: serverhash,
+                    oldhash: request.current_hash,
+                    hash_kind: H::KIND,
                 }
             }
-            None => {
+            Err(reason) => {
+                trace_event!(
+                    tracing::Level::DEBUG,
+                    reason = reason.as_str(),
+                    "sending a complete update"
+                );
+                self.stats.complete(reason);
+                clientstate.stats.complete(reason);
+                clientstate.confirmed = None;
                 let new: STATE = STATE::identity();
                 let complete_diff = new.diff(&self.state);
-                // send complete new update if the clients percieced hash and what the server thinks the client has differs
                 ClientUpdate::Complete {
                     complete_diff,
                     newhash: serverhash,
+                    hash_kind: H::KIND,
                 }
             }
         };
 
-        // After the update, assume that the client has updated information
-        self.client_states.insert(
-            request.id,
-            ClientState {
-                state: self.state.clone(),
-                hash: serverhash,
-            },
-        );
+        // Keep the sent version as pending until the client acks it, either explicitly or by
+        // requesting with its hash
+        clientstate.pending = (clientstate.confirmed != Some(head)).then_some(head);
+        self.store_client(request.id, clientstate);
+        self.evict();
+        #[cfg(feature = "tracing")]
+        {
+            span.record(
+                "newhash",
+                tracing::field::display(crate::trace::Hex(serverhash)),
+            );
+            span.record("kind", crate::trace::update_kind(&upd));
+        }
         upd
     }
+
+    /// Like [`Server::get_client_diff`], but for the view `project` makes of the state for the
+    /// client, see [`crate::projection`]. The client is kept with the others, in the version of
+    /// the state its view was projected from, with the hash of the view
+    pub(crate) fn get_view_diff<VIEW: Hash + Diff>(
+        &self,
+        request: ClientUpdateRequest<ID>,
+        project: impl Fn(&ID, &STATE) -> VIEW,
+    ) -> ClientUpdate<VIEW::Repr> {
+        if request.hash_kind != H::KIND {
+            log::warn!(
+                "Client hashes with {:?}, but the server with {:?}",
+                request.hash_kind,
+                H::KIND
+            );
+            return ClientUpdate::Diff {
+                diff: VIEW::identity().diff(&VIEW::identity()),
+                newhash: request.current_hash,
+                oldhash: request.current_hash,
+                hash_kind: H::KIND,
+            };
+        }
+        let head = self.commit_hash(self.calculate_hash());
+        let view = project(&request.id, &self.state);
+        let newhash = H::hash_of(&view);
+
+        let mut clientstate = self.touch_client(&request.id);
+        clientstate.confirm(request.current_hash);
+        self.stats.request();
+        clientstate.stats.request();
+
+        // views aren't kept, so the client's view is projected again from its version
+        let baseline = match clientstate.confirmed {
+            _ if request.force_complete => Err(CompleteReason::Requested),
+            Some(confirmed) if confirmed.hash == request.current_hash => {
+                let history = self.read_history();
+                history
+                    .find_version(confirmed.version)
+                    .and_then(|version| history.state(version))
+                    .map(|state| project(&request.id, &state))
+                    .ok_or(CompleteReason::AgedOut)
+            }
+            _ => Err(CompleteReason::UnknownVersion),
+        };
+
+        let upd = match baseline {
+            Ok(baseline) => {
+                self.stats.diff(None);
+                clientstate.stats.diff(None);
+                ClientUpdate::Diff {
+                    diff: baseline.diff(&view),
+                    newhash,
+                    oldhash: request.current_hash,
+                    hash_kind: H::KIND,
+                }
+            }
+            Err(reason) => {
+                trace_event!(
+                    tracing::Level::DEBUG,
+                    reason = reason.as_str(),
+                    "sending a complete view"
+                );
+                self.stats.complete(reason);
+                clientstate.stats.complete(reason);
+                clientstate.confirmed = None;
+                ClientUpdate::Complete {
+                    complete_diff: VIEW::identity().diff(&view),
+                    newhash,
+                    hash_kind: H::KIND,
+                }
+            }
+        };
+
+        let sent = VersionRef {
+            version: head.version,
+            hash: newhash,
+        };
+        if clientstate.confirmed.is_some_and(|c| c.hash == newhash) {
+            // the view didn't change, so the client is in the latest version already
+            clientstate.confirmed = Some(sent);
+            clientstate.pending = None;
+        } else {
+            clientstate.pending = Some(sent);
+        }
+        self.store_client(request.id, clientstate);
+        self.evict();
+        upd
+    }
+
+    /// Answer a reconciliation request for the map selected from the state, see
+    /// [`crate::merkle`]. The state is committed first, so that a client done reconciling gets
+    /// a diff on its next update request
+    pub fn reconcile<M: MerkleMap>(
+        &self,
+        map: impl Fn(&STATE) -> &M,
+        request: ReconcileRequest<M::Key>,
+    ) -> ReconcileResponse<M::Key, M::Value> {
+        self.commit();
+        merkle::respond::<H, M>(map(&self.state), request)
+    }
+
+    /// Merge changes pushed by a client into the state using the configured [`ConflictPolicy`],
+    /// and answer with the update that brings the client to the merged state. Pushes based on a
+    /// version that is no longer in the history are rejected with [`PushError::UnknownBase`],
+    /// unless the client attached that version, see [`Client::push_rejected`].
+    ///
+    /// The diff comes from the client, so applying it may fail, panicking in the diff's `apply`
+    /// for instance for a list diff with indices past the end of the list. Such pushes are
+    /// rejected with [`PushError::InvalidPush`], which needs panics to unwind
+    ///
+    /// [`Client::push_rejected`]: crate::client::Client::push_rejected
+    pub fn apply_push(
+        &mut self,
+        push: ClientPush<ID, STATE::Repr>,
+    ) -> Result<ClientUpdate<STATE::Repr>, PushError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "apply_push",
+            client = ?push.id,
+            trace_id = push.trace_id,
+            base_hash = %crate::trace::Hex(push.base_hash),
+        )
+        .entered();
+        self.stats.push();
+        let head = self.commit_hash(self.calculate_hash());
+        let invalid = PushError::InvalidPush {
+            base_hash: push.base_hash,
+        };
+
+        let (base, server_timestamp) = {
+            let history = self.read_history();
+            let base = history
+                .find_hash(push.base_hash)
+                .and_then(|version| history.state(version));
+            let server_timestamp = history.committed_at(head);
+            (base, server_timestamp.unwrap_or_default())
+        };
+        let base = match (base, &push.base) {
+            (Some(base), _) => base,
+            (None, Some(attached)) => {
+                let base = Arc::new(apply_untrusted(&STATE::identity(), attached).ok_or(invalid)?);
+                if H::hash_of(&*base) != push.base_hash {
+                    return Err(invalid);
+                }
+                base
+            }
+            (None, None) => {
+                log::warn!(
+                    "Rejecting push based on unknown version {:X}",
+                    push.base_hash
+                );
+                return Err(PushError::UnknownBase {
+                    base_hash: push.base_hash,
+                });
+            }
+        };
+
+        // the state the client is in after making its changes
+        let Some(pushed) = apply_untrusted(&*base, &push.diff) else {
+            log::warn!(
+                "Rejecting push that can't be applied to {:X}",
+                push.base_hash
+            );
+            return Err(invalid);
+        };
+        let pushed_hash = H::hash_of(&pushed);
+
+        self.state = self.conflict_policy.resolve(&Conflict {
+            base: &base,
+            base_hash: push.base_hash,
+            server: &self.state,
+            server_hash: head.hash,
+            pushed: &pushed,
+            client_diff: &push.diff,
+            client_timestamp: push.timestamp,
+            server_timestamp,
+        });
+        let newhash = self.calculate_hash();
+        let head = self.commit_hash(newhash);
+
+        // the pushed state is not a version we know, so there is nothing confirmed to fall back to
+        let mut clientstate = self.touch_client(&push.id);
+        clientstate.stats.push();
+        clientstate.confirmed = None;
+        clientstate.pending = Some(head);
+        self.store_client(push.id, clientstate);
+        self.evict();
+
+        Ok(ClientUpdate::Diff {
+            diff: pushed.diff(&self.state),
+            newhash,
+            oldhash: pushed_hash,
+            hash_kind: H::KIND,
+        })
+    }
+}
+
+/// Apply a diff received from a client to a copy of `base`, or `None` if applying it panics
+fn apply_untrusted<STATE: Diff + Clone>(base: &STATE, diff: &STATE::Repr) -> Option<STATE> {
+    std::panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut applied = base.clone();
+        applied.apply(diff);
+        applied
+    }))
+    .ok()
+}
+
+impl<STATE, ID, H> Server<STATE, ID, H>
+where
+    STATE: Hash + Clone + Diff + Serialize + DeserializeOwned,
+    STATE::Repr: Serialize + DeserializeOwned,
+    ID: Hash + Ord + Clone + TracedId + Serialize + DeserializeOwned + Send + Sync + 'static,
+    H: HashAlgorithm,
+{
+    /// Write the state, the history and the version of every client, so that a server loaded
+    /// from it can keep sending diffs. The content type is written first, so it doesn't have to
+    /// be known when loading. Configuration and counters are not saved
+    pub fn save_to<W: Write>(&self, writer: W, content_type: ContentType) -> io::Result<()> {
+        let mut clients = Vec::new();
+        self.client_states.for_each(&mut |id, clientstate| {
+            clients.push((id.clone(), clientstate.confirmed, clientstate.pending))
+        })?;
+        let history = self.read_history();
+        let saved = Saved {
+            state: &self.state,
+            next_version: history.next_version,
+            versions: history
+                .versions
+                .iter()
+                .map(|snapshot| SavedVersion {
+                    version: snapshot.version,
+                    committed_at: snapshot.committed_at,
+                    state: snapshot.state.as_deref(),
+                    diff: snapshot.diff.as_deref(),
+                })
+                .collect(),
+            clients,
+        };
+        codec::write_tagged(writer, content_type, &saved)
+    }
+
+    /// Load a server written by [`Server::save_to`], configure it with the builder methods as
+    /// usual
+    pub fn load_from<R: Read>(reader: R) -> io::Result<Self> {
+        let saved: Saved<STATE, STATE::Repr, ID> = codec::read_tagged(reader)?;
+
+        let server = Self::new(saved.state);
+        let mut history = server
+            .history
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        history.next_version = saved.next_version;
+        history.versions = saved
+            .versions
+            .into_iter()
+            .map(|saved| Snapshot {
+                version: saved.version,
+                committed_at: saved.committed_at,
+                state: saved.state.map(Arc::new),
+                diff: saved.diff.map(Arc::new),
+                size: 0,
+            })
+            .collect();
+        let max_len = history.max_len.max(history.versions.len());
+        history.set_max_len(max_len);
+        history.head_state = history.head().and_then(|head| history.state(head));
+        history.since_keyframe = history
+            .versions
+            .iter()
+            .rev()
+            .take_while(|snapshot| snapshot.state.is_none())
+            .count();
+        drop(history);
+        for (id, confirmed, pending) in saved.clients {
+            server.client_states.put(
+                id,
+                ClientState {
+                    confirmed,
+                    pending,
+                    ..Default::default()
+                },
+            )?;
+        }
+        Ok(server)
+    }
+
+    /// Save to a file, replacing it atomically so that a crash while saving leaves the previous
+    /// save intact
+    pub fn save_file(&self, path: impl AsRef<Path>, content_type: ContentType) -> io::Result<()> {
+        codec::replace_file(path.as_ref(), |file| self.save_to(file, content_type))
+    }
+
+    pub fn load_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::load_from(io::BufReader::new(fs::File::open(path)?))
+    }
+
+    /// Like [`Server::save_to`], but into memory, so that the server can be released before
+    /// the save is written out, see [`save_periodically`]
+    pub fn save_to_vec(&self, content_type: ContentType) -> io::Result<Vec<u8>> {
+        let mut saved = Vec::new();
+        self.save_to(&mut saved, content_type)?;
+        Ok(saved)
+    }
+}
+
+/// Save a server to a file at a fixed interval forever, on a thread of its own. `snapshot` is
+/// called for every save, and should lock the server only for as long as it takes to call
+/// [`Server::save_to_vec`], the file is written and synced to disk after it returns, replacing
+/// the previous save atomically like [`Server::save_file`]. Failures are logged, and the next
+/// save tried as usual.
+///
+/// For a server shared with tokio's `RwLock`, `snapshot` can take the lock with
+/// `blocking_read`, as it isn't called on a runtime thread
+pub fn save_periodically(
+    path: impl Into<PathBuf>,
+    period: Duration,
+    mut snapshot: impl FnMut() -> io::Result<Vec<u8>> + Send + 'static,
+) -> thread::JoinHandle<()> {
+    let path = path.into();
+    thread::spawn(move || loop {
+        thread::sleep(period);
+        let saved =
+            snapshot().and_then(|saved| codec::replace_file(&path, |file| file.write_all(&saved)));
+        if let Err(e) = saved {
+            log::warn!("Saving the server failed: {e}");
+        }
+    })
 }
-#[derive(Debug, Default)]
-pub struct ClientState<STATE> {
+
+/// What [`Server::save_to`] writes, borrowed when saving and owned when loading
+#[derive(Serialize, Deserialize)]
+struct Saved<STATE, REPR, ID> {
     state: STATE,
-    hash: u64,
+    next_version: u64,
+    versions: Vec<SavedVersion<STATE, REPR>>,
+    clients: Vec<(ID, Option<VersionRef>, Option<VersionRef>)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedVersion<STATE, REPR> {
+    version: VersionRef,
+    committed_at: u64,
+    state: Option<STATE>,
+    diff: Option<REPR>,
+}
+
+/// The versions the server knows a client to be in, the confirmed one has been acked by the
+/// client, while the pending one has been sent but not yet acked. Opaque to a
+/// [`ClientStateStore`], which only has to keep it, or serialize it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientState {
+    confirmed: Option<VersionRef>,
+    pending: Option<VersionRef>,
+    stats: SyncCounters,
+    /// milliseconds since the unix epoch
+    last_seen: u64,
+    /// Order of the last request, for least recently used eviction
+    last_use: u64,
+}
+
+impl Default for ClientState {
+    fn default() -> Self {
+        Self {
+            confirmed: None,
+            pending: None,
+            stats: Default::default(),
+            last_seen: now_millis(),
+            last_use: 0,
+        }
+    }
+}
+
+/// Limits on the number of clients kept
+struct Eviction {
+    idle_timeout: Option<Duration>,
+    max_clients: Option<usize>,
+    next_use: AtomicU64,
+    started: Instant,
+    /// Milliseconds after `started` of the last idle sweep
+    last_sweep: AtomicU64,
+}
+
+impl Eviction {
+    fn new() -> Self {
+        Self {
+            idle_timeout: None,
+            max_clients: None,
+            next_use: AtomicU64::new(0),
+            started: Instant::now(),
+            last_sweep: AtomicU64::new(0),
+        }
+    }
+
+    /// Stamp for a client that has just been used, later uses get higher stamps
+    fn next_use(&self) -> u64 {
+        self.next_use.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// The idle timeout, if it is time to sweep for idle clients. Sweeping visits every client,
+    /// so it is only due a few times per timeout
+    fn sweep_due(&self) -> Option<Duration> {
+        let timeout = self.idle_timeout?;
+        let now = self.started.elapsed().as_millis() as u64;
+        let last = self.last_sweep.load(Ordering::Relaxed);
+        let interval = (timeout.as_millis() as u64 / 4).max(1);
+        (now.saturating_sub(last) >= interval
+            && self
+                .last_sweep
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok())
+        .then_some(timeout)
+    }
+}
+
+/// The last use of the clients to keep to stay within `max_clients`, given the last uses of all
+/// of them. `None` if there is nothing to evict. Goes by the uses given, as clients may have
+/// come and gone since they were counted
+fn oldest_kept(uses: &mut [u64], max_clients: usize) -> Option<u64> {
+    let excess = uses.len().saturating_sub(max_clients);
+    if excess == 0 || excess >= uses.len() {
+        return None;
+    }
+    let (_, &mut oldest_kept, _) = uses.select_nth_unstable(excess);
+    Some(oldest_kept)
+}
+
+impl ClientState {
+    /// Promote the pending version to confirmed if the hash matches it
+    fn confirm(&mut self, hash: StateHash) {
+        if matches!(self.pending, Some(pending) if pending.hash == hash) {
+            self.confirmed = self.pending.take();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct VersionRef {
+    version: u64,
+    hash: StateHash,
+}
+
+struct Snapshot<STATE: Diff> {
+    version: VersionRef,
+    /// milliseconds since the unix epoch
+    committed_at: u64,
+    /// A copy of the state, kept for every version unless the history stores a delta chain,
+    /// then only for keyframes and the oldest version
+    state: Option<Arc<STATE>>,
+    /// The diff from the previous version, only kept for a delta chain
+    diff: Option<Arc<STATE::Repr>>,
+    /// Estimated size of `state`, when there is a budget
+    size: usize,
+}
+
+/// How the history stores versions
+enum Storage<R> {
+    Snapshots,
+    Deltas {
+        keyframe_interval: usize,
+        compose: fn(&R, &R) -> R,
+    },
+}
+
+/// What to diff a client against
+enum Baseline<STATE: Diff> {
+    State(Arc<STATE>),
+    /// Diffs to compose, in order
+    Chain(
+        fn(&STATE::Repr, &STATE::Repr) -> STATE::Repr,
+        Vec<Arc<STATE::Repr>>,
+    ),
+}
+
+/// Bounded ring of committed versions, oldest first
+struct History<STATE: Diff> {
+    versions: VecDeque<Snapshot<STATE>>,
+    max_len: usize,
+    next_version: u64,
+    storage: Storage<STATE::Repr>,
+    /// The latest state, to diff the next version against
+    head_state: Option<Arc<STATE>>,
+    /// Versions committed since the last one with a copy of the state
+    since_keyframe: usize,
+    /// Bytes the copies of the state may take up, and how to estimate their size
+    budget: Option<(usize, SizeFn<STATE>)>,
+}
+
+/// Estimates the size of a state in bytes
+pub type SizeFn<STATE> = fn(&STATE) -> usize;
+
+impl<STATE: Diff> History<STATE> {
+    fn new(max_len: usize) -> Self {
+        Self {
+            versions: VecDeque::new(),
+            max_len: max_len.max(1),
+            next_version: 0,
+            storage: Storage::Snapshots,
+            head_state: None,
+            since_keyframe: 0,
+            budget: None,
+        }
+    }
+
+    fn set_max_len(&mut self, max_len: usize) {
+        // the head always has to be kept, as it is what clients are pending on. Older versions
+        // are dropped on the next commit
+        self.max_len = max_len.max(1);
+    }
+}
+
+impl<STATE: Diff + Clone> History<STATE> {
+    fn truncate(&mut self) {
+        while self.versions.len() > self.max_len || self.over_budget() {
+            let Some(oldest) = self.versions.pop_front() else {
+                break;
+            };
+            // the oldest version is always kept whole, as later ones are rebuilt from it
+            if let (Some(state), Some(next)) = (oldest.state, self.versions.front_mut()) {
+                if let (None, Some(diff)) = (&next.state, &next.diff) {
+                    let mut state = STATE::clone(&state);
+                    state.apply(diff);
+                    next.size = self.budget.map_or(0, |(_, size_of)| size_of(&state));
+                    next.state = Some(Arc::new(state));
+                }
+            }
+        }
+    }
+
+    fn over_budget(&self) -> bool {
+        let Some((bytes, _)) = self.budget else {
+            return false;
+        };
+        self.versions.len() > 1 && self.versions.iter().map(|s| s.size).sum::<usize>() > bytes
+    }
+
+    fn head(&self) -> Option<VersionRef> {
+        self.versions.back().map(|snapshot| snapshot.version)
+    }
+
+    fn push(&mut self, state: Arc<STATE>, hash: StateHash) -> VersionRef {
+        let version = VersionRef {
+            version: self.next_version,
+            hash,
+        };
+        self.next_version += 1;
+        let (state_copy, diff) = match (&self.storage, &self.head_state) {
+            (
+                Storage::Deltas {
+                    keyframe_interval, ..
+                },
+                Some(previous),
+            ) => {
+                let diff = Arc::new(previous.diff(&state));
+                self.since_keyframe += 1;
+                if self.since_keyframe >= *keyframe_interval {
+                    self.since_keyframe = 0;
+                    (Some(state.clone()), Some(diff))
+                } else {
+                    (None, Some(diff))
+                }
+            }
+            _ => {
+                self.since_keyframe = 0;
+                (Some(state.clone()), None)
+            }
+        };
+        let size = match (&state_copy, self.budget) {
+            (Some(state), Some((_, size_of))) => size_of(state),
+            _ => 0,
+        };
+        self.versions.push_back(Snapshot {
+            version,
+            committed_at: now_millis(),
+            state: state_copy,
+            diff,
+            size,
+        });
+        self.head_state = Some(state);
+        self.truncate();
+        version
+    }
+
+    fn position(&self, version: VersionRef) -> Option<usize> {
+        self.versions
+            .iter()
+            .position(|snapshot| snapshot.version == version)
+    }
+
+    fn contains(&self, version: VersionRef) -> bool {
+        self.position(version).is_some()
+    }
+
+    fn committed_at(&self, version: VersionRef) -> Option<u64> {
+        let position = self.position(version)?;
+        Some(self.versions[position].committed_at)
+    }
+
+    fn find_version(&self, version: u64) -> Option<VersionRef> {
+        self.versions
+            .iter()
+            .find(|snapshot| snapshot.version.version == version)
+            .map(|snapshot| snapshot.version)
+    }
+
+    fn find_hash(&self, hash: StateHash) -> Option<VersionRef> {
+        self.versions
+            .iter()
+            .rev()
+            .find(|snapshot| snapshot.version.hash == hash)
+            .map(|snapshot| snapshot.version)
+    }
+
+    /// The state of a version, rebuilt from the closest keyframe before it if needed
+    fn state(&self, version: VersionRef) -> Option<Arc<STATE>> {
+        let position = self.position(version)?;
+        if let Some(state) = &self.versions[position].state {
+            return Some(state.clone());
+        }
+        let keyframe = self
+            .versions
+            .range(..position)
+            .rposition(|snapshot| snapshot.state.is_some())?;
+        let mut state = STATE::clone(self.versions[keyframe].state.as_ref()?);
+        for snapshot in self.versions.range(keyframe + 1..=position) {
+            state.apply(snapshot.diff.as_ref()?);
+        }
+        Some(Arc::new(state))
+    }
+
+    /// What to diff a client in `version` against to bring it to `head`, or `None` if it
+    /// should get a complete update instead
+    fn baseline(&self, version: VersionRef, head: VersionRef) -> Option<Baseline<STATE>> {
+        if version == head {
+            return self.head_state.clone().map(Baseline::State);
+        }
+        let Storage::Deltas {
+            keyframe_interval,
+            compose,
+        } = self.storage
+        else {
+            return self.state(version).map(Baseline::State);
+        };
+        let position = self.position(version)?;
+        let end = self.position(head)?;
+        // rebuilding the client's version from the keyframe before it takes one diff per
+        // version in between, plus diffing a whole state, which a keyframe interval's worth of
+        // diffs is taken to cost
+        let keyframe = self
+            .versions
+            .range(..=position)
+            .rposition(|snapshot| snapshot.state.is_some())?;
+        if end - position > position - keyframe + keyframe_interval {
+            return self.state(version).map(Baseline::State);
+        }
+        let start = position + 1;
+        let diffs: Option<Vec<_>> = self
+            .versions
+            .range(start..=end)
+            .map(|snapshot| snapshot.diff.clone())
+            .collect();
+        match diffs {
+            Some(diffs) => Some(Baseline::Chain(compose, diffs)),
+            // versions committed before switching to a delta chain only have a copy
+            None => self.state(version).map(Baseline::State),
+        }
+    }
 }
@@ -0,0 +1,303 @@
+//! Counters for how well syncing works. A [`crate::server::Server`] keeps them per client and
+//! in aggregate, see [`crate::server::Server::stats`]. With the `metrics` feature they are also
+//! reported through the [`metrics`](https://docs.rs/metrics) facade, as:
+//!
+//! - `diffsync_requests_total`, `diffsync_pushes_total`, `diffsync_acks_total`
+//! - `diffsync_updates_total`, labelled with `kind` (`diff` or `complete`) and for complete
+//!   updates the `reason`
+//! - `diffsync_diff_entries` and `diffsync_update_bytes` histograms
+//! - `diffsync_hash_seconds` histogram
+//! - `diffsync_apply_failures_total` on clients, labelled with the `error`
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use super::*;
+
+/// Why a client was sent a complete update instead of a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CompleteReason {
+    /// The client is in a version the server doesn't know, usually because it is new
+    UnknownVersion,
+    /// The version the client confirmed has been dropped from the history
+    AgedOut,
+    /// The client's version could not be rebuilt from the delta chain
+    TooFarBehind,
+    /// The client asked for it, see [`crate::ClientUpdateRequest`]
+    Requested,
+}
+
+impl CompleteReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::UnknownVersion => "unknown_version",
+            Self::AgedOut => "aged_out",
+            Self::TooFarBehind => "too_far_behind",
+            Self::Requested => "requested",
+        }
+    }
+}
+
+/// Snapshot of the counters of a server, or of a single client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct SyncStats {
+    pub requests: u64,
+    pub pushes: u64,
+    pub acks: u64,
+    pub diffs: u64,
+    pub completes_unknown_version: u64,
+    pub completes_aged_out: u64,
+    pub completes_too_far_behind: u64,
+    pub completes_requested: u64,
+    /// Entries in all diffs sent, if the server knows how to count them, see
+    /// [`crate::server::Server::with_diff_entries`]
+    pub diff_entries: u64,
+    /// Encoded size of all updates sent, as recorded by the transport
+    pub update_bytes: u64,
+    /// Updates whose encoded size was recorded
+    pub encoded_updates: u64,
+}
+
+impl SyncStats {
+    pub fn completes(&self) -> u64 {
+        self.completes_unknown_version
+            + self.completes_aged_out
+            + self.completes_too_far_behind
+            + self.completes_requested
+    }
+
+    /// The share of updates that were diffs, `None` before the first update
+    pub fn diff_ratio(&self) -> Option<f64> {
+        let updates = self.diffs + self.completes();
+        (updates > 0).then(|| self.diffs as f64 / updates as f64)
+    }
+
+    /// Mean encoded size of an update, `None` if no sizes were recorded
+    pub fn mean_update_bytes(&self) -> Option<f64> {
+        (self.encoded_updates > 0).then(|| self.update_bytes as f64 / self.encoded_updates as f64)
+    }
+}
+
+/// Snapshot of the counters of a server, see [`crate::server::Server::stats`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ServerStats {
+    pub sync: SyncStats,
+    /// Number of clients currently kept track of
+    pub clients: u64,
+    /// States hashed, and the time it took
+    pub hashes: u64,
+    pub hash_time: Duration,
+}
+
+impl ServerStats {
+    /// Mean time to hash the state, `None` before the first hash
+    pub fn mean_hash_time(&self) -> Option<Duration> {
+        (self.hashes > 0).then(|| self.hash_time / self.hashes as u32)
+    }
+}
+
+/// The counters behind [`SyncStats`], stored as a snapshot with the client states
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(from = "SyncStats", into = "SyncStats")]
+pub(crate) struct SyncCounters {
+    requests: AtomicU64,
+    pushes: AtomicU64,
+    acks: AtomicU64,
+    diffs: AtomicU64,
+    completes_unknown_version: AtomicU64,
+    completes_aged_out: AtomicU64,
+    completes_too_far_behind: AtomicU64,
+    completes_requested: AtomicU64,
+    diff_entries: AtomicU64,
+    update_bytes: AtomicU64,
+    encoded_updates: AtomicU64,
+}
+
+fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+impl SyncCounters {
+    pub(crate) fn request(&self) {
+        add(&self.requests, 1);
+    }
+
+    pub(crate) fn push(&self) {
+        add(&self.pushes, 1);
+    }
+
+    pub(crate) fn ack(&self) {
+        add(&self.acks, 1);
+    }
+
+    pub(crate) fn diff(&self, entries: Option<usize>) {
+        add(&self.diffs, 1);
+        if let Some(entries) = entries {
+            add(&self.diff_entries, entries as u64);
+        }
+    }
+
+    pub(crate) fn complete(&self, reason: CompleteReason) {
+        add(
+            match reason {
+                CompleteReason::UnknownVersion => &self.completes_unknown_version,
+                CompleteReason::AgedOut => &self.completes_aged_out,
+                CompleteReason::TooFarBehind => &self.completes_too_far_behind,
+                CompleteReason::Requested => &self.completes_requested,
+            },
+            1,
+        );
+    }
+
+    pub(crate) fn encoded(&self, bytes: usize) {
+        add(&self.update_bytes, bytes as u64);
+        add(&self.encoded_updates, 1);
+    }
+
+    pub(crate) fn snapshot(&self) -> SyncStats {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        SyncStats {
+            requests: get(&self.requests),
+            pushes: get(&self.pushes),
+            acks: get(&self.acks),
+            diffs: get(&self.diffs),
+            completes_unknown_version: get(&self.completes_unknown_version),
+            completes_aged_out: get(&self.completes_aged_out),
+            completes_too_far_behind: get(&self.completes_too_far_behind),
+            completes_requested: get(&self.completes_requested),
+            diff_entries: get(&self.diff_entries),
+            update_bytes: get(&self.update_bytes),
+            encoded_updates: get(&self.encoded_updates),
+        }
+    }
+}
+
+impl Clone for SyncCounters {
+    fn clone(&self) -> Self {
+        self.snapshot().into()
+    }
+}
+
+impl From<SyncStats> for SyncCounters {
+    fn from(stats: SyncStats) -> Self {
+        Self {
+            requests: stats.requests.into(),
+            pushes: stats.pushes.into(),
+            acks: stats.acks.into(),
+            diffs: stats.diffs.into(),
+            completes_unknown_version: stats.completes_unknown_version.into(),
+            completes_aged_out: stats.completes_aged_out.into(),
+            completes_too_far_behind: stats.completes_too_far_behind.into(),
+            completes_requested: stats.completes_requested.into(),
+            diff_entries: stats.diff_entries.into(),
+            update_bytes: stats.update_bytes.into(),
+            encoded_updates: stats.encoded_updates.into(),
+        }
+    }
+}
+
+impl From<SyncCounters> for SyncStats {
+    fn from(counters: SyncCounters) -> Self {
+        counters.snapshot()
+    }
+}
+
+/// The aggregate counters of a server, which also feed the `metrics` facade
+#[derive(Debug, Default)]
+pub(crate) struct ServerCounters {
+    pub(crate) sync: SyncCounters,
+    hashes: AtomicU64,
+    hash_nanos: AtomicU64,
+}
+
+impl ServerCounters {
+    pub(crate) fn request(&self) {
+        self.sync.request();
+        #[cfg(feature = "metrics")]
+        metrics::counter!("diffsync_requests_total").increment(1);
+    }
+
+    pub(crate) fn push(&self) {
+        self.sync.push();
+        #[cfg(feature = "metrics")]
+        metrics::counter!("diffsync_pushes_total").increment(1);
+    }
+
+    pub(crate) fn ack(&self) {
+        self.sync.ack();
+        #[cfg(feature = "metrics")]
+        metrics::counter!("diffsync_acks_total").increment(1);
+    }
+
+    pub(crate) fn diff(&self, entries: Option<usize>) {
+        self.sync.diff(entries);
+        #[cfg(feature = "metrics")]
+        {
+            metrics::counter!("diffsync_updates_total", "kind" => "diff").increment(1);
+            if let Some(entries) = entries {
+                metrics::histogram!("diffsync_diff_entries").record(entries as f64);
+            }
+        }
+    }
+
+    pub(crate) fn complete(&self, reason: CompleteReason) {
+        self.sync.complete(reason);
+        #[cfg(feature = "metrics")]
+        metrics::counter!("diffsync_updates_total", "kind" => "complete", "reason" => reason.as_str())
+            .increment(1);
+    }
+
+    pub(crate) fn encoded(&self, bytes: usize) {
+        self.sync.encoded(bytes);
+        #[cfg(feature = "metrics")]
+        metrics::histogram!("diffsync_update_bytes").record(bytes as f64);
+    }
+
+    pub(crate) fn hashed(&self, time: Duration) {
+        add(&self.hashes, 1);
+        add(&self.hash_nanos, time.as_nanos() as u64);
+        #[cfg(feature = "metrics")]
+        metrics::histogram!("diffsync_hash_seconds").record(time.as_secs_f64());
+    }
+
+    pub(crate) fn snapshot(&self, clients: usize) -> ServerStats {
+        ServerStats {
+            sync: self.sync.snapshot(),
+            clients: clients as u64,
+            hashes: self.hashes.load(Ordering::Relaxed),
+            hash_time: Duration::from_nanos(self.hash_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Failed [`crate::client::Client::apply_update`]s, by error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ApplyFailures {
+    pub invalid_update_start_state: u64,
+    pub hash_result_diff: u64,
+    pub hash_kind_mismatch: u64,
+}
+
+impl ApplyFailures {
+    pub(crate) fn record(&mut self, error: &UpdateError) {
+        let (counter, _name) = match error {
+            UpdateError::InvalidUpdateStartState { .. } => (
+                &mut self.invalid_update_start_state,
+                "invalid_update_start_state",
+            ),
+            UpdateError::HashResultDiff { .. } => (&mut self.hash_result_diff, "hash_result_diff"),
+            UpdateError::HashKindMismatch { .. } => {
+                (&mut self.hash_kind_mismatch, "hash_kind_mismatch")
+            }
+        };
+        *counter += 1;
+        #[cfg(feature = "metrics")]
+        metrics::counter!("diffsync_apply_failures_total", "error" => _name).increment(1);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.invalid_update_start_state + self.hash_result_diff + self.hash_kind_mismatch
+    }
+}
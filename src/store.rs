@@ -0,0 +1,185 @@
+//! Where a [`crate::server::Server`] keeps what it knows of every client. By default that is a
+//! [`DashMap`] in memory, with the `redb` feature the states can also be kept on disk with
+//! `RedbStore`, for servers with more clients than they want to keep in memory.
+//!
+//! A store that fails doesn't fail the server, the error is logged and the client is treated
+//! as unknown, so it gets a complete update instead of a diff.
+
+use std::io;
+
+use dashmap::DashMap;
+
+use crate::server::ClientState;
+
+use super::*;
+
+/// Storage of the [`ClientState`] of every client. The server doesn't hold on to a client
+/// between getting and putting its state, so concurrent requests of the same client are settled
+/// by the last put, which at worst costs the client a complete update
+pub trait ClientStateStore<ID>: Send + Sync {
+    fn get(&self, id: &ID) -> io::Result<Option<ClientState>>;
+
+    /// Store the state of a client, replacing any earlier one
+    fn put(&self, id: ID, state: ClientState) -> io::Result<()>;
+
+    fn remove(&self, id: &ID) -> io::Result<Option<ClientState>>;
+
+    /// Visit every client, in no particular order
+    fn for_each(&self, f: &mut dyn FnMut(&ID, &ClientState)) -> io::Result<()>;
+
+    /// Remove the clients `keep` returns `false` for, returning how many were removed
+    fn retain(&self, keep: &mut dyn FnMut(&ID, &ClientState) -> bool) -> io::Result<usize>;
+
+    fn len(&self) -> io::Result<usize>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+impl<ID: Hash + Eq + Send + Sync> ClientStateStore<ID> for DashMap<ID, ClientState> {
+    fn get(&self, id: &ID) -> io::Result<Option<ClientState>> {
+        Ok(DashMap::get(self, id).map(|clientstate| clientstate.clone()))
+    }
+
+    fn put(&self, id: ID, state: ClientState) -> io::Result<()> {
+        self.insert(id, state);
+        Ok(())
+    }
+
+    fn remove(&self, id: &ID) -> io::Result<Option<ClientState>> {
+        Ok(DashMap::remove(self, id).map(|(_, clientstate)| clientstate))
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&ID, &ClientState)) -> io::Result<()> {
+        self.iter().for_each(|entry| f(entry.key(), entry.value()));
+        Ok(())
+    }
+
+    fn retain(&self, keep: &mut dyn FnMut(&ID, &ClientState) -> bool) -> io::Result<usize> {
+        let before = DashMap::len(self);
+        DashMap::retain(self, |id, clientstate| keep(id, clientstate));
+        Ok(before - DashMap::len(self))
+    }
+
+    fn len(&self) -> io::Result<usize> {
+        Ok(DashMap::len(self))
+    }
+}
+
+#[cfg(feature = "redb")]
+pub use on_disk::RedbStore;
+
+#[cfg(feature = "redb")]
+mod on_disk {
+    use std::{marker::PhantomData, path::Path};
+
+    use redb::{Database, Durability, ReadableTable, ReadableTableMetadata, TableDefinition};
+    use serde::de::DeserializeOwned;
+
+    use super::*;
+
+    const CLIENTS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("clients");
+
+    /// Client states in a [redb](https://docs.rs/redb) database, keyed and encoded with bincode
+    pub struct RedbStore<ID> {
+        db: Database,
+        id: PhantomData<fn(ID) -> ID>,
+    }
+
+    fn other(e: impl Into<redb::Error>) -> io::Error {
+        io::Error::other(e.into())
+    }
+
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+        bincode::deserialize(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    impl<ID> RedbStore<ID> {
+        /// Open the database at `path`, creating it if it doesn't exist
+        pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+            let db = Database::create(path).map_err(other)?;
+            let txn = db.begin_write().map_err(other)?;
+            txn.open_table(CLIENTS).map_err(other)?;
+            txn.commit().map_err(other)?;
+            Ok(Self {
+                db,
+                id: PhantomData,
+            })
+        }
+
+        /// Change the clients in a write transaction. Losing the last writes in a crash only
+        /// costs those clients a complete update, so they aren't synced to disk right away
+        fn write<T>(
+            &self,
+            f: impl FnOnce(&mut redb::Table<&[u8], &[u8]>) -> io::Result<T>,
+        ) -> io::Result<T> {
+            let mut txn = self.db.begin_write().map_err(other)?;
+            txn.set_durability(Durability::Eventual);
+            let result = f(&mut txn.open_table(CLIENTS).map_err(other)?)?;
+            txn.commit().map_err(other)?;
+            Ok(result)
+        }
+
+        fn read(&self) -> io::Result<redb::ReadOnlyTable<&'static [u8], &'static [u8]>> {
+            let txn = self.db.begin_read().map_err(other)?;
+            txn.open_table(CLIENTS).map_err(other)
+        }
+    }
+
+    impl<ID: Serialize + DeserializeOwned> ClientStateStore<ID> for RedbStore<ID> {
+        fn get(&self, id: &ID) -> io::Result<Option<ClientState>> {
+            let table = self.read()?;
+            let value = table.get(encode(id)?.as_slice()).map_err(other)?;
+            value.map(|value| decode(value.value())).transpose()
+        }
+
+        fn put(&self, id: ID, state: ClientState) -> io::Result<()> {
+            let (key, value) = (encode(&id)?, encode(&state)?);
+            self.write(|table| {
+                table
+                    .insert(key.as_slice(), value.as_slice())
+                    .map_err(other)?;
+                Ok(())
+            })
+        }
+
+        fn remove(&self, id: &ID) -> io::Result<Option<ClientState>> {
+            let key = encode(id)?;
+            self.write(|table| {
+                let removed = table.remove(key.as_slice()).map_err(other)?;
+                removed.map(|value| decode(value.value())).transpose()
+            })
+        }
+
+        fn for_each(&self, f: &mut dyn FnMut(&ID, &ClientState)) -> io::Result<()> {
+            for entry in self.read()?.iter().map_err(other)? {
+                let (key, value) = entry.map_err(other)?;
+                f(&decode(key.value())?, &decode(value.value())?);
+            }
+            Ok(())
+        }
+
+        fn retain(&self, keep: &mut dyn FnMut(&ID, &ClientState) -> bool) -> io::Result<usize> {
+            self.write(|table| {
+                let before = table.len().map_err(other)?;
+                // entries that fail to decode are kept, so that nothing is lost to a bug
+                table
+                    .retain(|key, value| match (decode(key), decode(value)) {
+                        (Ok(id), Ok(clientstate)) => keep(&id, &clientstate),
+                        _ => true,
+                    })
+                    .map_err(other)?;
+                Ok((before - table.len().map_err(other)?) as usize)
+            })
+        }
+
+        fn len(&self) -> io::Result<usize> {
+            Ok(self.read()?.len().map_err(other)? as usize)
+        }
+    }
+}
@@ -5,9 +5,8 @@ use super::*;
 /// Simple diff implementation instead of the nesting one found in diff-struct,
 /// that would run "diff" recursively down into
 /// the value stored in the map
-
 #[cfg_attr(feature = "impl_schemars", derive(schemars::JsonSchema))]
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct SimpleDiff<K: Ord, V> {
     pub altered: BTreeMap<K, V>,
     pub removed: BTreeSet<K>,
@@ -29,6 +28,34 @@ impl<K: Ord, V> SimpleDiff<K, V> {
             removed: Default::default(),
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.altered.is_empty() && self.removed.is_empty()
+    }
+
+    /// Number of keys altered or removed
+    pub fn len(&self) -> usize {
+        self.altered.len() + self.removed.len()
+    }
+}
+
+impl<K: Clone + Ord, V: Clone> SimpleDiff<K, V> {
+    /// A single diff that has the same effect as applying `a` and then `b`
+    pub fn compose(a: &Self, b: &Self) -> Self {
+        let mut altered = a.altered.clone();
+        for key in &b.removed {
+            altered.remove(key);
+        }
+        altered.extend(b.altered.iter().map(|(k, v)| (k.clone(), v.clone())));
+        // a key that ends up altered doesn't have to be removed first
+        let removed = a
+            .removed
+            .union(&b.removed)
+            .filter(|key| !altered.contains_key(*key))
+            .cloned()
+            .collect();
+        Self { altered, removed }
+    }
 }
 
 pub trait SimpleDiffTrait<T> {
@@ -36,6 +63,13 @@ pub trait SimpleDiffTrait<T> {
     fn apply_to(&self, apply_to: &mut T);
 }
 
+/// Diffs that can be undone, kept apart from [`SimpleDiffTrait`] so that implementing that
+/// doesn't require it
+pub trait InvertDiff<T>: SimpleDiffTrait<T> {
+    /// The diff that undoes this one, once it has been applied to `base`
+    fn invert(&self, base: &T) -> Self;
+}
+
 // macro_rules! impl_map {
 //     ($ty:ty) => {
 //         impl<K: Clone + Ord, V: Clone + PartialEq> SimpleDiffTrait<$ty <K, V>> for SimpleDiff<K, V> {
@@ -83,7 +117,7 @@ impl<K: Clone + Ord, V: Clone + PartialEq> SimpleDiffTrait<BTreeMap<K, V>> for S
 
         // Check for alterations, dont nest into the value struct for diff
         for (key, value) in a.iter() {
-            if let Some(other_value) = b.get(&key) {
+            if let Some(other_value) = b.get(key) {
                 // don't store values that don't change
                 if value != other_value {
                     diff.altered.insert(key.clone(), other_value.clone());
@@ -94,7 +128,7 @@ impl<K: Clone + Ord, V: Clone + PartialEq> SimpleDiffTrait<BTreeMap<K, V>> for S
         }
         // Check what to remove
         for (key, value) in b {
-            if let None = a.get(&key) {
+            if !a.contains_key(key) {
                 diff.altered.insert(key.clone(), value.clone());
             }
         }
@@ -110,15 +144,24 @@ impl<K: Clone + Ord, V: Clone + PartialEq> SimpleDiffTrait<BTreeMap<K, V>> for S
         }
     }
 }
-impl<K: Hash + Clone + Ord, V: Clone + PartialEq> SimpleDiffTrait<ConcMap<K, V>>
-    for SimpleDiff<K, V>
+
+impl<K: Clone + Ord, V: Clone + PartialEq> InvertDiff<BTreeMap<K, V>> for SimpleDiff<K, V> {
+    fn invert(&self, base: &BTreeMap<K, V>) -> Self {
+        invert(self, |key| base.get(key).cloned())
+    }
+}
+impl<K: Hash + Clone + Ord, V: Clone + PartialEq + Hash, H: HashAlgorithm>
+    SimpleDiffTrait<ConcMap<K, V, H>> for SimpleDiff<K, V>
 {
-    fn generate(a: &ConcMap<K, V>, b: &ConcMap<K, V>) -> Self {
+    fn generate(a: &ConcMap<K, V, H>, b: &ConcMap<K, V, H>) -> Self {
+        if let Some(diff) = b.changes_since(a) {
+            return diff;
+        }
         let mut diff: SimpleDiff<K, V> = Default::default();
 
         // Check for alterations, dont nest into the value struct for diff
-        for r in a.0.iter() {
-            if let Some(other_value) = b.0.get(&r.key()) {
+        for r in a.iter() {
+            if let Some(other_value) = b.get(r.key()) {
                 // don't store values that don't change
                 if r.value() != other_value.value() {
                     diff.altered.insert(r.key().clone(), other_value.clone());
@@ -128,22 +171,51 @@ impl<K: Hash + Clone + Ord, V: Clone + PartialEq> SimpleDiffTrait<ConcMap<K, V>>
             }
         }
         // Check what to remove
-        for r in &b.0 {
-            if let None = a.0.get(&r.key()) {
+        for r in b {
+            if !a.contains_key(r.key()) {
                 diff.altered.insert(r.key().clone(), r.value().clone());
             }
         }
 
         diff
     }
-    fn apply_to(&self, apply_to: &mut ConcMap<K, V>) {
+    fn apply_to(&self, apply_to: &mut ConcMap<K, V, H>) {
         self.removed.iter().for_each(|del| {
-            apply_to.0.remove(del);
+            apply_to.remove(del);
         });
         for (key, change) in &self.altered {
-            apply_to.0.insert(key.clone(), change.clone());
+            apply_to.insert(key.clone(), change.clone());
+        }
+    }
+}
+
+impl<K: Hash + Clone + Ord, V: Clone + PartialEq + Hash, H: HashAlgorithm>
+    InvertDiff<ConcMap<K, V, H>> for SimpleDiff<K, V>
+{
+    fn invert(&self, base: &ConcMap<K, V, H>) -> Self {
+        invert(self, |key| base.get(key).map(|r| r.value().clone()))
+    }
+}
+
+/// Restore every key the diff touches to its value in the base, removing the ones it didn't have
+fn invert<K: Clone + Ord, V>(
+    diff: &SimpleDiff<K, V>,
+    base: impl Fn(&K) -> Option<V>,
+) -> SimpleDiff<K, V> {
+    let mut inverse = SimpleDiff::new();
+    for key in diff.removed.iter().chain(diff.altered.keys()) {
+        match base(key) {
+            Some(value) => {
+                inverse.altered.insert(key.clone(), value);
+            }
+            None => {
+                inverse.removed.insert(key.clone());
+            }
         }
     }
+    // removing a key the base never had changes nothing, so there is nothing to undo
+    inverse.removed.retain(|key| diff.altered.contains_key(key));
+    inverse
 }
 
 // impl<K: Clone + Ord, V: Clone + PartialEq> SimpleDiff<K, V> {
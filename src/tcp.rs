@@ -0,0 +1,498 @@
+//! Length prefixed TCP transport, serving a shared [`Server`](crate::server::Server) to any
+//! number of connections, and a driver that keeps a [`Client`](crate::client::Client) in sync by
+//! polling over a single connection.
+//!
+//! Messages are sent in the frames of the [`crate::codec`] module. Clients send
+//! [`ClientMessage`]s, and every update or push is answered with a
+//! [`SyncAnswer`](crate::tcp::SyncAnswer) in the same content type as the request, so clients
+//! using different codecs can share a server.
+//!
+//! Compression is configured separately on each side with a
+//! [`CompressionConfig`](crate::compression::CompressionConfig), frames are always decompressed
+//! according to their flag, within the limit of the receiving side.
+
+use std::{fmt::Debug, io, sync::Arc, time::Duration};
+
+use serde::de::DeserializeOwned;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Mutex, RwLock},
+    task::JoinHandle,
+};
+
+use crate::{
+    client::Client,
+    codec::{decode_frame_body, encode_frame_with, frame_len, ContentType},
+    compression::CompressionConfig,
+    customhash::XxHash64,
+    server::Server,
+    trace::TracedId,
+};
+
+use super::*;
+
+/// A server shared between all connections and the rest of the application
+pub type SharedServer<STATE, ID, H = XxHash64> = Arc<RwLock<Server<STATE, ID, H>>>;
+
+/// The answer to an update request or a push, only pushes can be rejected
+pub type SyncAnswer<T> = Result<ClientUpdate<T>, PushError>;
+
+/// A client shared between its driver and the rest of the application
+pub type SharedClient<STATE, ID, H = XxHash64> = Arc<Mutex<Client<STATE, ID, H>>>;
+
+/// Accept connections on the listener forever, serving each one on its own task
+pub async fn serve<STATE, ID, H>(
+    listener: TcpListener,
+    server: SharedServer<STATE, ID, H>,
+) -> io::Result<()>
+where
+    STATE: Hash + Clone + Diff + Send + Sync + 'static,
+    STATE::Repr: Serialize + DeserializeOwned + Send + Sync,
+    ID: Hash + Ord + TracedId + DeserializeOwned + Send + Sync + 'static,
+    H: HashAlgorithm + 'static,
+{
+    serve_with_compression(listener, server, CompressionConfig::default()).await
+}
+
+/// Like [`serve`], compressing the answers according to the configuration
+pub async fn serve_with_compression<STATE, ID, H>(
+    listener: TcpListener,
+    server: SharedServer<STATE, ID, H>,
+    compression: CompressionConfig,
+) -> io::Result<()>
+where
+    STATE: Hash + Clone + Diff + Send + Sync + 'static,
+    STATE::Repr: Serialize + DeserializeOwned + Send + Sync,
+    ID: Hash + Ord + TracedId + DeserializeOwned + Send + Sync + 'static,
+    H: HashAlgorithm + 'static,
+{
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let server = server.clone();
+        let compression = compression.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, server, &compression).await {
+                log::warn!("Connection from {addr} closed: {e}");
+            }
+        });
+    }
+}
+
+/// Serve a single connection until the client disconnects
+pub async fn serve_connection<STATE, ID, H>(
+    mut stream: TcpStream,
+    server: SharedServer<STATE, ID, H>,
+    compression: &CompressionConfig,
+) -> io::Result<()>
+where
+    STATE: Hash + Clone + Diff,
+    STATE::Repr: Serialize + DeserializeOwned,
+    ID: Hash + Ord + TracedId + DeserializeOwned,
+    H: HashAlgorithm,
+{
+    stream.set_nodelay(true)?;
+    while let Some((content_type, message)) = read_message(&mut stream, compression).await? {
+        if let Some(answer) = handle_message(&server, message).await {
+            let frame = encode_frame_with(content_type, compression, &answer)?;
+            server.read().await.record_encoded_update(frame.len());
+            stream.write_all(&frame).await?;
+            stream.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Let the server handle a message from a client, returning the answer if there is one
+pub async fn handle_message<STATE, ID, H>(
+    server: &SharedServer<STATE, ID, H>,
+    message: ClientMessage<ID, STATE::Repr>,
+) -> Option<SyncAnswer<STATE::Repr>>
+where
+    STATE: Hash + Clone + Diff,
+    ID: Hash + Ord + TracedId,
+    H: HashAlgorithm,
+{
+    match message {
+        ClientMessage::Update(request) => Some(Ok(server.read().await.get_client_diff(request))),
+        ClientMessage::Push(push) => Some(server.write().await.apply_push(push)),
+        ClientMessage::Ack(ack) => {
+            server.read().await.acknowledge(ack);
+            None
+        }
+    }
+}
+
+/// Keeps a client in sync with a server by polling it at a fixed interval, reconnecting with an
+/// exponential backoff whenever the connection fails
+#[derive(Debug, Clone)]
+pub struct ClientDriver {
+    content_type: ContentType,
+    compression: CompressionConfig,
+    poll_interval: Duration,
+    min_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for ClientDriver {
+    fn default() -> Self {
+        Self {
+            content_type: ContentType::Bincode,
+            compression: CompressionConfig::default(),
+            poll_interval: Duration::from_secs(1),
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ClientDriver {
+    /// The codec to talk to the server with, defaults to bincode
+    pub fn with_content_type(mut self, content_type: ContentType) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    /// How pushes are compressed, and how large updates may become when decompressed
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// How long to wait between update requests
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// The backoff starts at `min` after a failure and doubles up to `max` while failures continue
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max.max(min);
+        self
+    }
+
+    /// Run the driver on a new task, abort the returned handle to stop it
+    pub fn spawn<A, STATE, ID, H>(
+        self,
+        addr: A,
+        client: SharedClient<STATE, ID, H>,
+    ) -> JoinHandle<()>
+    where
+        A: ToSocketAddrs + Clone + Send + Sync + 'static,
+        STATE: Hash + Diff + Default + Send + 'static,
+        STATE::Repr: Serialize + DeserializeOwned + Send + Sync,
+        ID: Clone + TracedId + Serialize + Send + Sync + 'static,
+        H: HashAlgorithm + 'static,
+    {
+        tokio::spawn(self.run(addr, client))
+    }
+
+    /// Keep the client in sync forever
+    pub async fn run<A, STATE, ID, H>(self, addr: A, client: SharedClient<STATE, ID, H>)
+    where
+        A: ToSocketAddrs + Clone,
+        STATE: Hash + Diff + Default,
+        STATE::Repr: Serialize + DeserializeOwned,
+        ID: Clone + TracedId + Serialize,
+        H: HashAlgorithm,
+    {
+        let mut backoff = self.min_backoff;
+        loop {
+            if let Err(e) = self
+                .run_connection(addr.clone(), &client, &mut backoff)
+                .await
+            {
+                log::warn!("Sync connection failed: {e}, retrying in {backoff:?}");
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+
+    /// Poll over a single connection until it fails, resetting the backoff once it has synced
+    async fn run_connection<A, STATE, ID, H>(
+        &self,
+        addr: A,
+        client: &SharedClient<STATE, ID, H>,
+        backoff: &mut Duration,
+    ) -> io::Result<()>
+    where
+        A: ToSocketAddrs,
+        STATE: Hash + Diff + Default,
+        STATE::Repr: Serialize + DeserializeOwned,
+        ID: Clone + TracedId + Serialize,
+        H: HashAlgorithm,
+    {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let mut interval = tokio::time::interval(self.poll_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            sync_once(&mut stream, self.content_type, &self.compression, client).await?;
+            *backoff = self.min_backoff;
+        }
+    }
+}
+
+/// Do a single round trip over the stream, pushing local changes if there are any and
+/// otherwise requesting an update
+pub async fn sync_once<S, STATE, ID, H>(
+    stream: &mut S,
+    content_type: ContentType,
+    compression: &CompressionConfig,
+    client: &SharedClient<STATE, ID, H>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    STATE: Hash + Diff + Default,
+    STATE::Repr: Serialize + DeserializeOwned,
+    ID: Clone + TracedId + Serialize,
+    H: HashAlgorithm,
+{
+    let message = sync_message(&*client.lock().await);
+    write_message(stream, content_type, compression, &message).await?;
+    let (_, answer) = read_message(stream, compression)
+        .await?
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    if let Some(ack) = apply_sync_update(&mut *client.lock().await, answer) {
+        let ack = ClientMessage::<ID, STATE::Repr>::Ack(ack);
+        write_message(stream, content_type, compression, &ack).await?;
+    }
+    Ok(())
+}
+
+/// The message a client sends to sync, its local changes if there are any and otherwise an
+/// update request
+pub fn sync_message<STATE, ID, H>(client: &Client<STATE, ID, H>) -> ClientMessage<ID, STATE::Repr>
+where
+    STATE: Hash + Diff + Default,
+    ID: Clone + TracedId,
+    H: HashAlgorithm,
+{
+    match client.push_request() {
+        Some(push) => ClientMessage::Push(push),
+        None => ClientMessage::Update(client.update_request()),
+    }
+}
+
+/// Apply the answer to a sync message, returning the ack to send if it applied. A rejected push
+/// is handed to [`Client::push_rejected`]
+pub fn apply_sync_update<STATE, ID, H>(
+    client: &mut Client<STATE, ID, H>,
+    answer: SyncAnswer<STATE::Repr>,
+) -> Option<ClientAck<ID>>
+where
+    STATE: Hash + Diff + Default,
+    ID: Clone + TracedId,
+    H: HashAlgorithm,
+{
+    let update = match answer {
+        Ok(update) => update,
+        Err(e) => {
+            log::warn!("Push was rejected: {e}");
+            client.push_rejected(&e);
+            return None;
+        }
+    };
+    match client.apply_update(update) {
+        Ok(()) => Some(client.acknowledgement()),
+        Err(e) => {
+            // the next request asks for a complete update
+            log::warn!("Failed to apply update: {e}");
+            None
+        }
+    }
+}
+
+/// Read one frame, returns `None` if the stream was closed cleanly before it
+pub async fn read_message<S: AsyncRead + Unpin, T: DeserializeOwned>(
+    stream: &mut S,
+    compression: &CompressionConfig,
+) -> io::Result<Option<(ContentType, T)>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let body_len = frame_len(&len)?.expect("holds the length") - 4;
+    // the length is whatever the peer claims, so only allocate for bytes that actually arrive
+    let mut body = Vec::with_capacity(body_len.min(READ_CHUNK_LEN));
+    stream.take(body_len as u64).read_to_end(&mut body).await?;
+    if body.len() < body_len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(decode_frame_body(&body, compression)?))
+}
+
+/// How much of a frame is allocated for up front, larger frames grow as they are read
+const READ_CHUNK_LEN: usize = 64 * 1024;
+
+/// Write one frame
+pub async fn write_message<S: AsyncWrite + Unpin, T: Serialize>(
+    stream: &mut S,
+    content_type: ContentType,
+    compression: &CompressionConfig,
+    message: &T,
+) -> io::Result<()> {
+    let frame = encode_frame_with(content_type, compression, message)?;
+    stream.write_all(&frame).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Deserialize, Serialize, Diff, Debug, Clone, Hash, PartialEq, Default)]
+    #[diff(attr(#[derive(Serialize, Deserialize)]))]
+    struct Data {
+        values: BTreeMap<u32, String>,
+    }
+
+    async fn wait_for_sync(client: &SharedClient<Data, u32>, server: &SharedServer<Data, u32>) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if client.lock().await.state == server.read().await.state {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("client never synced");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sync_over_localhost() {
+        let server: SharedServer<Data, u32> = Default::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = tokio::spawn(serve(listener, server.clone()));
+
+        let driver = ClientDriver::default().with_poll_interval(Duration::from_millis(10));
+        let clients: Vec<SharedClient<Data, u32>> = (0..3)
+            .map(|id| Arc::new(Mutex::new(Client::with_id(id).with_push())))
+            .collect();
+        // every client talks in its own codec
+        let content_types = [
+            ContentType::Bincode,
+            #[cfg(feature = "json")]
+            ContentType::Json,
+            #[cfg(feature = "cbor")]
+            ContentType::Cbor,
+        ];
+        let drivers: Vec<_> = clients
+            .iter()
+            .zip(content_types.iter().cycle())
+            .map(|(client, &content_type)| {
+                driver
+                    .clone()
+                    .with_content_type(content_type)
+                    .spawn(addr, client.clone())
+            })
+            .collect();
+
+        for i in 0..10 {
+            server.write().await.state.values.insert(i, format!("{i}"));
+        }
+        for client in &clients {
+            wait_for_sync(client, &server).await;
+        }
+
+        // local changes are pushed back
+        clients[0]
+            .lock()
+            .await
+            .state
+            .values
+            .insert(100, "from client".into());
+        for client in &clients {
+            wait_for_sync(client, &server).await;
+        }
+        assert!(server.read().await.state.values.contains_key(&100));
+
+        drivers.iter().for_each(JoinHandle::abort);
+        serving.abort();
+    }
+
+    #[tokio::test]
+    async fn truncated_frame_is_an_error() {
+        let config = CompressionConfig::default();
+        let mut frame = codec::encode_frame(ContentType::Bincode, "message").unwrap();
+        // claims to be far longer than what arrives
+        frame[..4].copy_from_slice(&(codec::MAX_FRAME_LEN as u32).to_be_bytes());
+        let e = read_message::<_, String>(&mut frame.as_slice(), &config)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        let frame = codec::encode_frame(ContentType::Bincode, "message").unwrap();
+        let (_, message) = read_message::<_, String>(&mut frame.as_slice(), &config)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message, "message");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reconnects_after_server_restart() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let client: SharedClient<Data, u32> = Arc::new(Mutex::new(Client::with_id(1)));
+        let driver = ClientDriver::default()
+            .with_poll_interval(Duration::from_millis(10))
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(50))
+            .spawn(addr, client.clone());
+
+        // the server only comes up after the client started trying
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let server: SharedServer<Data, u32> = Default::default();
+        server.write().await.state.values.insert(1, "one".into());
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let serving = tokio::spawn(serve(listener, server.clone()));
+
+        wait_for_sync(&client, &server).await;
+        driver.abort();
+        serving.abort();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn compressed_updates() {
+        use crate::compression::Compression;
+
+        let server: SharedServer<Data, u32> = Default::default();
+        for i in 0..10_000 {
+            server.write().await.state.values.insert(i, "value".into());
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let compression = CompressionConfig::new(Compression::Zstd).with_threshold(1024);
+        let serving = tokio::spawn(serve_with_compression(
+            listener,
+            server.clone(),
+            compression,
+        ));
+
+        let client: SharedClient<Data, u32> = Arc::new(Mutex::new(Client::with_id(1)));
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let limited = CompressionConfig::default().with_max_decompressed_len(1024);
+        let e = sync_once(&mut stream, ContentType::Bincode, &limited, &client)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let config = CompressionConfig::default();
+        sync_once(&mut stream, ContentType::Bincode, &config, &client)
+            .await
+            .unwrap();
+        assert_eq!(client.lock().await.state, server.read().await.state);
+        serving.abort();
+    }
+}
@@ -0,0 +1,75 @@
+//! Support for the `tracing` feature. Update requests and pushes carry a trace id, which the
+//! client and server spans handling them both record, so that they can be correlated.
+
+#[cfg(feature = "tracing")]
+use super::*;
+
+/// Identifies an update request or push, and the update answering it
+pub type TraceId = u64;
+
+/// Bound on client ids, which spans record with their `Debug` implementation when the `tracing`
+/// feature is enabled. Without it any id will do
+#[cfg(feature = "tracing")]
+pub trait TracedId: std::fmt::Debug {}
+
+#[cfg(feature = "tracing")]
+impl<T: std::fmt::Debug + ?Sized> TracedId for T {}
+
+/// Bound on client ids, which spans record with their `Debug` implementation when the `tracing`
+/// feature is enabled. Without it any id will do
+#[cfg(not(feature = "tracing"))]
+pub trait TracedId {}
+
+#[cfg(not(feature = "tracing"))]
+impl<T: ?Sized> TracedId for T {}
+
+/// A fresh trace id, only made when tracing is enabled
+pub(crate) fn new_trace_id() -> Option<TraceId> {
+    #[cfg(feature = "tracing")]
+    {
+        use std::{
+            collections::hash_map::RandomState,
+            hash::BuildHasher,
+            sync::{
+                atomic::{AtomicU64, Ordering},
+                OnceLock,
+            },
+        };
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        // randomly keyed, so that ids of different processes don't collide
+        static KEYS: OnceLock<RandomState> = OnceLock::new();
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        Some(KEYS.get_or_init(RandomState::new).hash_one(count))
+    }
+    #[cfg(not(feature = "tracing"))]
+    None
+}
+
+/// Displays a hash in hex, for span fields
+#[cfg(feature = "tracing")]
+pub(crate) struct Hex(pub StateHash);
+
+#[cfg(feature = "tracing")]
+impl std::fmt::Display for Hex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:x}", self.0)
+    }
+}
+
+/// Name of the kind of an update, for span fields
+#[cfg(feature = "tracing")]
+pub(crate) fn update_kind<T>(update: &ClientUpdate<T>) -> &'static str {
+    match update {
+        ClientUpdate::Complete { .. } => "complete",
+        ClientUpdate::Diff { .. } => "diff",
+    }
+}
+
+/// `tracing::event!` with the `tracing` feature, nothing without it
+macro_rules! trace_event {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::event!($($arg)+);
+    };
+}
+pub(crate) use trace_event;
@@ -0,0 +1,216 @@
+//! WebSocket transport, for clients that can't open plain TCP connections, like browsers.
+//!
+//! Clients send [`ClientMessage`]s either as JSON in text frames, or in any other
+//! [`ContentType`](crate::codec::ContentType) in binary frames starting with its tag, and are
+//! answered with a [`SyncAnswer`](crate::tcp::SyncAnswer) in the same content type.
+
+// the errors are tungstenite's own, which is as large as it is
+#![allow(clippy::result_large_err)]
+
+use futures_util::{SinkExt, StreamExt};
+use serde::de::DeserializeOwned;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+use tokio_tungstenite::{
+    tungstenite::{self, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use crate::{
+    client::Client,
+    codec::ContentType,
+    tcp::{apply_sync_update, handle_message, sync_message, SharedServer},
+    trace::TracedId,
+};
+
+use super::*;
+
+pub use tungstenite::Error;
+
+/// Encode a message in a text frame for JSON, and in a binary frame tagged with the content type
+/// otherwise
+fn encode<T: Serialize>(content_type: ContentType, message: &T) -> Result<Message, Error> {
+    let bytes = content_type.encode(message).map_err(invalid_data)?;
+    Ok(match content_type {
+        ContentType::Json => Message::Text(String::from_utf8(bytes).map_err(invalid_data)?),
+        _ => Message::Binary([&[content_type.tag()], bytes.as_slice()].concat()),
+    })
+}
+
+/// Decode a data frame, returns `None` for control frames
+fn decode<T: DeserializeOwned>(message: &Message) -> Result<Option<(ContentType, T)>, Error> {
+    let (content_type, bytes) = match message {
+        Message::Text(text) => (ContentType::Json, text.as_bytes()),
+        Message::Binary(bytes) => {
+            let Some((&tag, bytes)) = bytes.split_first() else {
+                return Err(invalid_data("binary frame without content type"));
+            };
+            (ContentType::from_tag(tag).map_err(invalid_data)?, bytes)
+        }
+        _ => return Ok(None),
+    };
+    let message = content_type.decode(bytes).map_err(invalid_data)?;
+    Ok(Some((content_type, message)))
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> Error {
+    Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Accept WebSocket connections on the listener forever, serving each one on its own task
+pub async fn serve<STATE, ID, H>(
+    listener: TcpListener,
+    server: SharedServer<STATE, ID, H>,
+) -> std::io::Result<()>
+where
+    STATE: Hash + Clone + Diff + Send + Sync + 'static,
+    STATE::Repr: Serialize + DeserializeOwned + Send + Sync,
+    ID: Hash + Ord + TracedId + DeserializeOwned + Send + Sync + 'static,
+    H: HashAlgorithm + 'static,
+{
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, server).await {
+                log::warn!("WebSocket connection from {addr} closed: {e}");
+            }
+        });
+    }
+}
+
+/// Do the WebSocket handshake on an accepted connection, and serve it until it closes
+pub async fn serve_connection<S, STATE, ID, H>(
+    stream: S,
+    server: SharedServer<STATE, ID, H>,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    STATE: Hash + Clone + Diff,
+    STATE::Repr: Serialize + DeserializeOwned,
+    ID: Hash + Ord + TracedId + DeserializeOwned,
+    H: HashAlgorithm,
+{
+    let mut socket = tokio_tungstenite::accept_async(stream).await?;
+    while let Some(frame) = socket.next().await {
+        let frame = frame?;
+        if frame.is_close() {
+            break;
+        }
+        let Some((content_type, message)) = decode(&frame)? else {
+            continue;
+        };
+        if let Some(answer) = handle_message(&server, message).await {
+            let message = encode(content_type, &answer)?;
+            server.read().await.record_encoded_update(message.len());
+            socket.send(message).await?;
+        }
+    }
+    Ok(())
+}
+
+/// A client side connection to a WebSocket server
+pub struct Connection {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    content_type: ContentType,
+}
+
+impl Connection {
+    /// Connect to a server, for example `ws://127.0.0.1:8080`, talking in the given content type
+    pub async fn connect(url: &str, content_type: ContentType) -> Result<Self, Error> {
+        let (socket, _) = tokio_tungstenite::connect_async(url).await?;
+        Ok(Self {
+            socket,
+            content_type,
+        })
+    }
+
+    /// Do a single round trip, pushing local changes if there are any and otherwise requesting
+    /// an update
+    pub async fn sync<STATE, ID, H>(
+        &mut self,
+        client: &mut Client<STATE, ID, H>,
+    ) -> Result<(), Error>
+    where
+        STATE: Hash + Diff + Default,
+        STATE::Repr: Serialize + DeserializeOwned,
+        ID: Clone + TracedId + Serialize,
+        H: HashAlgorithm,
+    {
+        self.send(&sync_message(client)).await?;
+        let answer = loop {
+            let frame = self.socket.next().await.ok_or(Error::ConnectionClosed)??;
+            if let Some((_, answer)) = decode(&frame)? {
+                break answer;
+            }
+        };
+        if let Some(ack) = apply_sync_update(client, answer) {
+            self.send(&ClientMessage::<ID, STATE::Repr>::Ack(ack))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn send<T: Serialize>(&mut self, message: &T) -> Result<(), Error> {
+        self.socket.send(encode(self.content_type, message)?).await
+    }
+
+    /// Close the connection
+    pub async fn close(mut self) -> Result<(), Error> {
+        self.socket.close(None).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Deserialize, Serialize, Diff, Debug, Clone, Hash, PartialEq, Default)]
+    #[diff(attr(#[derive(Serialize, Deserialize)]))]
+    struct Data {
+        values: BTreeMap<u32, String>,
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sync_over_localhost() {
+        let server: SharedServer<Data, u32> = Default::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let serving = tokio::spawn(serve(listener, server.clone()));
+
+        for i in 0..10 {
+            server.write().await.state.values.insert(i, format!("{i}"));
+        }
+
+        let content_types = [
+            ContentType::Json,
+            ContentType::Bincode,
+            #[cfg(feature = "cbor")]
+            ContentType::Cbor,
+        ];
+        for (id, content_type) in (1..).zip(content_types) {
+            let mut client: Client<Data, u32> = Client::with_id(id).with_push();
+            let mut connection = Connection::connect(&url, content_type).await.unwrap();
+            connection.sync(&mut client).await.unwrap();
+            assert_eq!(client.state, server.read().await.state);
+
+            client.state.values.insert(100 + id, "from client".into());
+            connection.sync(&mut client).await.unwrap();
+            assert!(server.read().await.state.values.contains_key(&(100 + id)));
+
+            server
+                .write()
+                .await
+                .state
+                .values
+                .insert(id, "changed".into());
+            connection.sync(&mut client).await.unwrap();
+            assert_eq!(client.state, server.read().await.state);
+
+            connection.close().await.unwrap();
+        }
+        serving.abort();
+    }
+}